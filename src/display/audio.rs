@@ -0,0 +1,89 @@
+use crate::display::utils::prepare_uds_pass;
+use zbus::zvariant::Fd;
+use zbus::{dbus_proxy, Connection};
+use std::os::unix::net::UnixStream;
+use tokio::sync::RwLock;
+use tracing::info;
+use crate::display::audio_listener::{AudioInListener, AudioOutListener, PcmSink, PcmSource};
+use crate::display::error::DisplayError;
+
+#[dbus_proxy(
+    default_service = "org.qemu",
+    default_path = "/org/qemu/Display1/Audio",
+    interface = "org.qemu.Display1.Audio"
+)]
+pub trait Audio {
+    /// RegisterOutListener method
+    fn register_out_listener(&self, listener: Fd) -> zbus::Result<()>;
+
+    /// RegisterInListener method
+    fn register_in_listener(&self, listener: Fd) -> zbus::Result<()>;
+}
+
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct Audio {
+    #[derivative(Debug = "ignore")]
+    pub proxy: AudioProxy<'static>,
+    out_listener: RwLock<Option<Connection>>,
+    in_listener: RwLock<Option<Connection>>,
+}
+
+impl Audio {
+    pub async fn new(connection: &Connection, service: &str) -> Result<Self, DisplayError> {
+        let proxy = AudioProxy::builder(connection)
+            .destination(service.to_string())?
+            .build()
+            .await?;
+
+        Ok(Self {
+            proxy,
+            out_listener: RwLock::new(None),
+            in_listener: RwLock::new(None),
+        })
+    }
+
+    /// Plays the guest audio output into `sink`, until unregistered or the VM goes away.
+    pub async fn register_out_listener<S: PcmSink>(&self, sink: S) -> Result<(), DisplayError> {
+        let (p0, p1) = UnixStream::pair()?;
+        let p0 = prepare_uds_pass(&p0)?;
+
+        self.proxy.register_out_listener(p0).await?;
+
+        let connection = zbus::ConnectionBuilder::unix_stream(p1)
+            .p2p()
+            .serve_at("/org/qemu/Display1/AudioOutListener", AudioOutListener::new(sink))?
+            .build()
+            .await?;
+
+        *self.out_listener.write().await = Some(connection);
+        info!("Registered audio out listener");
+        Ok(())
+    }
+
+    pub async fn unregister_out_listener(&self) {
+        *self.out_listener.write().await = None;
+    }
+
+    /// Feeds the guest audio capture from `source`, until unregistered or the VM goes away.
+    pub async fn register_in_listener<S: PcmSource>(&self, source: S) -> Result<(), DisplayError> {
+        let (p0, p1) = UnixStream::pair()?;
+        let p0 = prepare_uds_pass(&p0)?;
+
+        self.proxy.register_in_listener(p0).await?;
+
+        let connection = zbus::ConnectionBuilder::unix_stream(p1)
+            .p2p()
+            .serve_at("/org/qemu/Display1/AudioInListener", AudioInListener::new(source))?
+            .build()
+            .await?;
+
+        *self.in_listener.write().await = Some(connection);
+        info!("Registered audio in listener");
+        Ok(())
+    }
+
+    pub async fn unregister_in_listener(&self) {
+        *self.in_listener.write().await = None;
+    }
+}
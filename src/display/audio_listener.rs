@@ -0,0 +1,284 @@
+use zbus::dbus_interface;
+use tracing::instrument;
+
+/// PCM format of a stream, from `Init`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PcmInfo {
+    pub bits: u8,
+    pub is_signed: bool,
+    pub is_float: bool,
+    pub freq: u32,
+    pub nchannels: u8,
+    pub bytes_per_frame: u32,
+    pub bytes_per_second: u32,
+    pub be: bool,
+}
+
+/// Volume of a stream, one value per channel from 0 to 255.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Volume {
+    pub mute: bool,
+    pub volume: Vec<u8>,
+}
+
+/// Receives the guest audio output. `id` tells the voices of the guest apart.
+#[async_trait::async_trait]
+pub trait PcmSink: 'static + Send + Sync {
+
+    async fn init(&mut self, id: u64, info: PcmInfo);
+
+    async fn fini(&mut self, id: u64);
+
+    async fn set_enabled(&mut self, id: u64, enabled: bool);
+
+    async fn set_volume(&mut self, id: u64, volume: Volume);
+
+    /// Interleaved PCM frames in the format given to `init`.
+    async fn write(&mut self, id: u64, data: Vec<u8>);
+}
+
+#[derive(Debug)]
+pub(crate) struct AudioOutListener<S: PcmSink> {
+    sink: S,
+}
+
+#[dbus_interface(name = "org.qemu.Display1.AudioOutListener")]
+impl<S: PcmSink> AudioOutListener<S> {
+    #[instrument(level = "debug", skip(self))]
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        id: u64,
+        bits: u8,
+        is_signed: bool,
+        is_float: bool,
+        freq: u32,
+        nchannels: u8,
+        bytes_per_frame: u32,
+        bytes_per_second: u32,
+        be: bool,
+    ) {
+        self.sink
+            .init(
+                id,
+                PcmInfo {
+                    bits,
+                    is_signed,
+                    is_float,
+                    freq,
+                    nchannels,
+                    bytes_per_frame,
+                    bytes_per_second,
+                    be,
+                },
+            )
+            .await;
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn fini(&mut self, id: u64) {
+        self.sink.fini(id).await;
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn set_enabled(&mut self, id: u64, enabled: bool) {
+        self.sink.set_enabled(id, enabled).await;
+    }
+
+    #[instrument(level = "debug", skip(self, volume))]
+    async fn set_volume(&mut self, id: u64, mute: bool, volume: Vec<u8>) {
+        self.sink.set_volume(id, Volume { mute, volume }).await;
+    }
+
+    #[instrument(level = "trace", skip(self, data))]
+    async fn write(&mut self, id: u64, data: Vec<u8>) {
+        self.sink.write(id, data).await;
+    }
+}
+
+impl<S: PcmSink> AudioOutListener<S> {
+    pub(crate) fn new(sink: S) -> Self {
+        Self { sink }
+    }
+}
+
+/// Provides the audio the guest captures, e.g. its microphone. `id` tells the voices apart.
+#[async_trait::async_trait]
+pub trait PcmSource: 'static + Send + Sync {
+
+    async fn init(&mut self, id: u64, info: PcmInfo);
+
+    async fn fini(&mut self, id: u64);
+
+    async fn set_enabled(&mut self, id: u64, enabled: bool);
+
+    async fn set_volume(&mut self, id: u64, volume: Volume);
+
+    /// Up to `size` bytes of interleaved PCM frames in the format given to `init`.
+    async fn read(&mut self, id: u64, size: u64) -> Vec<u8>;
+}
+
+#[derive(Debug)]
+pub(crate) struct AudioInListener<S: PcmSource> {
+    source: S,
+}
+
+#[dbus_interface(name = "org.qemu.Display1.AudioInListener")]
+impl<S: PcmSource> AudioInListener<S> {
+    #[instrument(level = "debug", skip(self))]
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        id: u64,
+        bits: u8,
+        is_signed: bool,
+        is_float: bool,
+        freq: u32,
+        nchannels: u8,
+        bytes_per_frame: u32,
+        bytes_per_second: u32,
+        be: bool,
+    ) {
+        self.source
+            .init(
+                id,
+                PcmInfo {
+                    bits,
+                    is_signed,
+                    is_float,
+                    freq,
+                    nchannels,
+                    bytes_per_frame,
+                    bytes_per_second,
+                    be,
+                },
+            )
+            .await;
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn fini(&mut self, id: u64) {
+        self.source.fini(id).await;
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn set_enabled(&mut self, id: u64, enabled: bool) {
+        self.source.set_enabled(id, enabled).await;
+    }
+
+    #[instrument(level = "debug", skip(self, volume))]
+    async fn set_volume(&mut self, id: u64, mute: bool, volume: Vec<u8>) {
+        self.source.set_volume(id, Volume { mute, volume }).await;
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn read(&mut self, id: u64, size: u64) -> Vec<u8> {
+        self.source.read(id, size).await
+    }
+}
+
+impl<S: PcmSource> AudioInListener<S> {
+    pub(crate) fn new(source: S) -> Self {
+        Self { source }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use zbus::{dbus_proxy, ConnectionBuilder, Guid};
+
+    /// QEMU side of the listener.
+    #[dbus_proxy(
+        default_service = "org.qemu",
+        default_path = "/org/qemu/Display1/AudioOutListener",
+        interface = "org.qemu.Display1.AudioOutListener"
+    )]
+    trait OutListener {
+        #[allow(clippy::too_many_arguments)]
+        fn init(
+            &self,
+            id: u64,
+            bits: u8,
+            is_signed: bool,
+            is_float: bool,
+            freq: u32,
+            nchannels: u8,
+            bytes_per_frame: u32,
+            bytes_per_second: u32,
+            be: bool,
+        ) -> zbus::Result<()>;
+
+        fn fini(&self, id: u64) -> zbus::Result<()>;
+
+        fn set_enabled(&self, id: u64, enabled: bool) -> zbus::Result<()>;
+
+        fn set_volume(&self, id: u64, mute: bool, volume: &[u8]) -> zbus::Result<()>;
+
+        fn write(&self, id: u64, data: &[u8]) -> zbus::Result<()>;
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingSink {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl PcmSink for RecordingSink {
+        async fn init(&mut self, id: u64, info: PcmInfo) {
+            self.calls.lock().unwrap().push(format!("init {} {}bit {}Hz {}ch", id, info.bits, info.freq, info.nchannels));
+        }
+
+        async fn fini(&mut self, id: u64) {
+            self.calls.lock().unwrap().push(format!("fini {}", id));
+        }
+
+        async fn set_enabled(&mut self, id: u64, enabled: bool) {
+            self.calls.lock().unwrap().push(format!("enabled {} {}", id, enabled));
+        }
+
+        async fn set_volume(&mut self, id: u64, volume: Volume) {
+            self.calls.lock().unwrap().push(format!("volume {} {} {:?}", id, volume.mute, volume.volume));
+        }
+
+        async fn write(&mut self, id: u64, data: Vec<u8>) {
+            self.calls.lock().unwrap().push(format!("write {} {:?}", id, data));
+        }
+    }
+
+    #[tokio::test]
+    async fn out_listener_dispatches_to_the_sink() {
+        let sink = RecordingSink::default();
+        let (qemu, listener) = UnixStream::pair().unwrap();
+        let guid = Guid::generate();
+        let (qemu, _listener) = tokio::try_join!(
+            ConnectionBuilder::unix_stream(qemu).server(&guid).p2p().build(),
+            ConnectionBuilder::unix_stream(listener)
+                .p2p()
+                .serve_at("/org/qemu/Display1/AudioOutListener", AudioOutListener::new(sink.clone()))
+                .unwrap()
+                .build(),
+        )
+        .unwrap();
+        let proxy = OutListenerProxy::new(&qemu).await.unwrap();
+
+        proxy.init(3, 16, true, false, 44100, 2, 4, 44100 * 4, false).await.unwrap();
+        proxy.set_enabled(3, true).await.unwrap();
+        proxy.set_volume(3, false, &[128, 255]).await.unwrap();
+        proxy.write(3, &[1, 2, 3, 4]).await.unwrap();
+        proxy.fini(3).await.unwrap();
+
+        assert_eq!(
+            *sink.calls.lock().unwrap(),
+            [
+                "init 3 16bit 44100Hz 2ch",
+                "enabled 3 true",
+                "volume 3 false [128, 255]",
+                "write 3 [1, 2, 3, 4]",
+                "fini 3",
+            ]
+        );
+    }
+}
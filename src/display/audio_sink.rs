@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, ChildStdin, Command};
+use tracing::{debug, warn};
+use crate::display::audio_listener::{PcmInfo, PcmSink, Volume};
+
+/// Builds the command playing raw PCM in the given format from its stdin, `None` when the
+/// player can't take that format.
+pub type PlayerCommand = fn(&PcmInfo) -> Option<Command>;
+
+/// Player process of one output voice.
+#[derive(Debug)]
+struct PlayerVoice {
+    info: PcmInfo,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    enabled: bool,
+    mute: bool,
+}
+
+/// `PcmSink` playing every voice of the guest through its own player process, such as
+/// `pacat` or `aplay`, fed the PCM data as is.
+///
+/// Mute is honored, the volume levels are left to the mixer of the host.
+#[derive(Debug)]
+pub struct PlayerSink {
+    player: PlayerCommand,
+    voices: HashMap<u64, PlayerVoice>,
+}
+
+impl PlayerSink {
+    pub fn new(player: PlayerCommand) -> Self {
+        Self {
+            player,
+            voices: HashMap::new(),
+        }
+    }
+
+    /// Plays through PulseAudio, or PipeWire with its Pulse server.
+    pub fn pulse() -> Self {
+        Self::new(pacat)
+    }
+
+    /// Plays through ALSA.
+    pub fn alsa() -> Self {
+        Self::new(aplay)
+    }
+
+    fn close(&mut self, id: u64) {
+        if let Some(mut voice) = self.voices.remove(&id) {
+            // Closing stdin lets the player drain what it has buffered and exit
+            drop(voice.stdin.take());
+            tokio::spawn(async move {
+                let _ = voice.child.wait().await;
+            });
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PcmSink for PlayerSink {
+    async fn init(&mut self, id: u64, info: PcmInfo) {
+        debug!("Audio out voice {} initialized: {:?}", id, info);
+        self.close(id);
+
+        let Some(mut command) = (self.player)(&info) else {
+            warn!("Unsupported audio out format: {:?}", info);
+            return;
+        };
+        let child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn();
+        match child {
+            Ok(mut child) => {
+                let stdin = child.stdin.take();
+                self.voices.insert(id, PlayerVoice { info, child, stdin, enabled: false, mute: false });
+            }
+            Err(e) => warn!("Failed to start the audio player: {}", e),
+        }
+    }
+
+    async fn fini(&mut self, id: u64) {
+        self.close(id);
+    }
+
+    async fn set_enabled(&mut self, id: u64, enabled: bool) {
+        if let Some(voice) = self.voices.get_mut(&id) {
+            voice.enabled = enabled;
+        }
+    }
+
+    async fn set_volume(&mut self, id: u64, volume: Volume) {
+        if let Some(voice) = self.voices.get_mut(&id) {
+            voice.mute = volume.mute;
+        }
+    }
+
+    async fn write(&mut self, id: u64, mut data: Vec<u8>) {
+        let Some(voice) = self.voices.get_mut(&id) else {
+            return;
+        };
+        if !voice.enabled {
+            return;
+        }
+        let Some(stdin) = voice.stdin.as_mut() else {
+            return;
+        };
+
+        if voice.mute {
+            data = silence(&voice.info, data.len());
+        }
+        if let Err(e) = stdin.write_all(&data).await {
+            warn!("Audio player of voice {} went away: {}", id, e);
+            voice.stdin = None;
+        }
+    }
+}
+
+/// `len` bytes of silence in the format of `info`, unsigned formats being centered.
+fn silence(info: &PcmInfo, len: usize) -> Vec<u8> {
+    if info.is_signed || info.is_float || info.bits == 0 {
+        return vec![0; len];
+    }
+
+    // The most significant byte holds the offset of half the range
+    let bytes_per_sample = (info.bits / 8).max(1) as usize;
+    let mut sample = vec![0; bytes_per_sample];
+    let msb = if info.be { 0 } else { bytes_per_sample - 1 };
+    sample[msb] = 0x80;
+    sample.iter().copied().cycle().take(len).collect()
+}
+
+/// `pacat` reading raw PCM in the format of `info`.
+pub fn pacat(info: &PcmInfo) -> Option<Command> {
+    let endian = if info.be { "be" } else { "le" };
+    let format = match (info.bits, info.is_float, info.is_signed) {
+        (8, false, false) => "u8".to_string(),
+        (16 | 24 | 32, false, true) => format!("s{}{}", info.bits, endian),
+        (32, true, _) => format!("float32{}", endian),
+        _ => return None,
+    };
+
+    let mut command = Command::new("pacat");
+    command
+        .arg("--playback")
+        .arg("--raw")
+        .arg(format!("--format={}", format))
+        .arg(format!("--rate={}", info.freq))
+        .arg(format!("--channels={}", info.nchannels));
+    Some(command)
+}
+
+/// `aplay` reading raw PCM in the format of `info`.
+pub fn aplay(info: &PcmInfo) -> Option<Command> {
+    let endian = if info.be { "BE" } else { "LE" };
+    let format = match (info.bits, info.is_float, info.is_signed) {
+        (8, false, false) => "U8".to_string(),
+        (8, false, true) => "S8".to_string(),
+        (16 | 32, false, true) => format!("S{}_{}", info.bits, endian),
+        (16 | 32, false, false) => format!("U{}_{}", info.bits, endian),
+        (24, false, true) => format!("S24_3{}", endian),
+        (32, true, _) => format!("FLOAT_{}", endian),
+        _ => return None,
+    };
+
+    let mut command = Command::new("aplay");
+    command
+        .arg("--quiet")
+        .args(["-t", "raw"])
+        .args(["-f", &format])
+        .args(["-r", &info.freq.to_string()])
+        .args(["-c", &info.nchannels.to_string()]);
+    Some(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn info(bits: u8, is_signed: bool) -> PcmInfo {
+        PcmInfo {
+            bits,
+            is_signed,
+            is_float: false,
+            freq: 48000,
+            nchannels: 2,
+            bytes_per_frame: 2 * bits as u32 / 8,
+            bytes_per_second: 48000 * 2 * bits as u32 / 8,
+            be: false,
+        }
+    }
+
+    /// Stands in for a player, saving what it is fed to the file named by `PLAYER_OUT`.
+    fn cat_to_file(_info: &PcmInfo) -> Option<Command> {
+        let mut command = Command::new("sh");
+        command.args(["-c", "cat > \"$PLAYER_OUT\""]);
+        Some(command)
+    }
+
+    async fn played(path: &std::path::Path, len: usize) -> Vec<u8> {
+        for _ in 0..100 {
+            if let Ok(data) = std::fs::read(path) {
+                if data.len() >= len {
+                    return data;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        std::fs::read(path).unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn enabled_voices_are_played() {
+        let path = std::env::temp_dir().join(format!("vm_streaming-player-{}", std::process::id()));
+        std::env::set_var("PLAYER_OUT", &path);
+
+        let mut sink = PlayerSink::new(cat_to_file);
+        sink.init(1, info(16, true)).await;
+        // Nothing is played before the voice is enabled
+        sink.write(1, vec![9; 4]).await;
+        sink.set_enabled(1, true).await;
+        sink.write(1, vec![1, 2, 3, 4]).await;
+        sink.set_volume(1, Volume { mute: true, volume: vec![255, 255] }).await;
+        sink.write(1, vec![5, 6, 7, 8]).await;
+        sink.fini(1).await;
+
+        assert_eq!(played(&path, 8).await, vec![1, 2, 3, 4, 0, 0, 0, 0]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unsigned_silence_is_centered() {
+        assert_eq!(silence(&info(8, false), 3), vec![0x80; 3]);
+        assert_eq!(silence(&info(16, false), 4), vec![0, 0x80, 0, 0x80]);
+        assert_eq!(silence(&info(16, true), 4), vec![0; 4]);
+    }
+
+    #[test]
+    fn player_formats() {
+        assert!(pacat(&info(16, true)).is_some());
+        assert!(pacat(&info(16, false)).is_none());
+        assert!(aplay(&info(24, true)).is_some());
+        assert!(aplay(&info(12, true)).is_none());
+    }
+}
@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+use std::f32::consts::TAU;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+use crate::display::audio_listener::{PcmInfo, PcmSource, Volume};
+
+/// Produces audio as interleaved `f32` samples in `[-1, 1]`, at whatever rate and channel
+/// count the guest asked for.
+pub trait SampleGenerator: 'static + Send + Sync {
+    fn fill(&mut self, freq: u32, nchannels: u8, samples: &mut [f32]);
+}
+
+/// How long `StreamSource::read` waits for the stream before padding with silence.
+const STREAM_READ_DEADLINE: Duration = Duration::from_millis(5);
+
+/// Whether samples in the format of `info` can be encoded: 8 to 32-bit integers or 32-bit
+/// floats, interleaved in whole frames.
+fn supported(info: &PcmInfo) -> bool {
+    let sample_ok = match info.bits {
+        8 | 16 | 24 => !info.is_float,
+        32 => true,
+        _ => false,
+    };
+    sample_ok && info.nchannels > 0 && info.bytes_per_frame == info.bits as u32 / 8 * info.nchannels as u32
+}
+
+/// State QEMU set up for one capture voice.
+#[derive(Debug)]
+struct Voice {
+    info: PcmInfo,
+    enabled: bool,
+    volume: Option<Volume>,
+}
+
+/// `PcmSource` that encodes a `SampleGenerator` into the format of each voice, applying
+/// its volume.
+#[derive(Debug)]
+pub struct GeneratorSource<G> {
+    generator: G,
+    voices: HashMap<u64, Voice>,
+}
+
+impl<G: SampleGenerator> GeneratorSource<G> {
+    pub fn new(generator: G) -> Self {
+        Self {
+            generator,
+            voices: HashMap::new(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<G: SampleGenerator> PcmSource for GeneratorSource<G> {
+    async fn init(&mut self, id: u64, info: PcmInfo) {
+        debug!("Audio in voice {} initialized: {:?}", id, info);
+        if !supported(&info) {
+            warn!("Unsupported audio in format: {:?}", info);
+            self.voices.remove(&id);
+            return;
+        }
+        self.voices.insert(id, Voice { info, enabled: false, volume: None });
+    }
+
+    async fn fini(&mut self, id: u64) {
+        self.voices.remove(&id);
+    }
+
+    async fn set_enabled(&mut self, id: u64, enabled: bool) {
+        if let Some(voice) = self.voices.get_mut(&id) {
+            voice.enabled = enabled;
+        }
+    }
+
+    async fn set_volume(&mut self, id: u64, volume: Volume) {
+        if let Some(voice) = self.voices.get_mut(&id) {
+            voice.volume = Some(volume);
+        }
+    }
+
+    async fn read(&mut self, id: u64, size: u64) -> Vec<u8> {
+        let Some(voice) = self.voices.get(&id) else {
+            return Vec::new();
+        };
+        let info = voice.info;
+        let nchannels = info.nchannels as usize;
+
+        let frames = size as usize / info.bytes_per_frame as usize;
+        let mut samples = vec![0.0; frames * nchannels];
+        if voice.enabled {
+            self.generator.fill(info.freq, info.nchannels, &mut samples);
+        }
+
+        let mut data = Vec::with_capacity(frames * info.bytes_per_frame as usize);
+        for (i, sample) in samples.iter().enumerate() {
+            let gain = match &voice.volume {
+                Some(volume) if volume.mute => 0.0,
+                Some(volume) => volume.volume.get(i % nchannels).map_or(1.0, |v| *v as f32 / 255.0),
+                None => 1.0,
+            };
+            encode_sample(&info, sample * gain, &mut data);
+        }
+        data
+    }
+}
+
+/// Encodes a sample in `[-1, 1]` in the PCM format of `info`.
+pub fn encode_sample(info: &PcmInfo, value: f32, out: &mut Vec<u8>) {
+    let value = value.clamp(-1.0, 1.0);
+    let bytes_per_sample = (info.bits / 8) as usize;
+    if bytes_per_sample == 0 {
+        return;
+    }
+
+    let raw = if info.is_float {
+        match info.bits {
+            64 => (value as f64).to_bits(),
+            _ => value.to_bits() as u64,
+        }
+    } else {
+        let half = 1i64 << (info.bits - 1);
+        let scaled = (value as f64 * (half - 1) as f64).round() as i64;
+        if info.is_signed {
+            scaled as u64
+        } else {
+            (scaled + half) as u64
+        }
+    };
+
+    let bytes = raw.to_le_bytes();
+    let bytes = &bytes[..bytes_per_sample.min(8)];
+    if info.be {
+        out.extend(bytes.iter().rev());
+    } else {
+        out.extend_from_slice(bytes);
+    }
+}
+
+/// Sine wave of `frequency` Hz on every channel, to test capture without a microphone.
+#[derive(Debug, Clone)]
+pub struct Sine {
+    pub frequency: f32,
+    pub amplitude: f32,
+    phase: f32,
+}
+
+impl Sine {
+    pub fn new(frequency: f32, amplitude: f32) -> Self {
+        Self {
+            frequency,
+            amplitude,
+            phase: 0.0,
+        }
+    }
+}
+
+impl SampleGenerator for Sine {
+    fn fill(&mut self, freq: u32, nchannels: u8, samples: &mut [f32]) {
+        let step = TAU * self.frequency / freq.max(1) as f32;
+        for frame in samples.chunks_mut(nchannels.max(1) as usize) {
+            frame.fill(self.amplitude * self.phase.sin());
+            self.phase = (self.phase + step) % TAU;
+        }
+    }
+}
+
+/// A WAV file decoded in memory, resampled and remixed to what the guest asks for.
+#[derive(Debug, Clone)]
+pub struct Wav {
+    pub freq: u32,
+    pub nchannels: u8,
+    /// Interleaved samples in `[-1, 1]`
+    samples: Vec<f32>,
+    /// Position in frames of the file
+    position: f64,
+    /// Starts over at the end instead of going silent
+    pub looping: bool,
+}
+
+impl Wav {
+    pub fn open(path: impl AsRef<Path>, looping: bool) -> io::Result<Self> {
+        let mut wav = Self::parse(&std::fs::read(path)?)?;
+        wav.looping = looping;
+        Ok(wav)
+    }
+
+    /// Decodes 8, 16, 24 and 32-bit integer PCM and 32-bit float RIFF files.
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+            return Err(invalid("Not a WAV file"));
+        }
+
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+
+        let mut format = None;
+        let mut samples = None;
+        let mut offset = 12;
+        while offset + 8 <= data.len() {
+            let id = &data[offset..offset + 4];
+            let size = u32_at(offset + 4) as usize;
+            let body = data.get(offset + 8..offset + 8 + size).ok_or_else(|| invalid("Truncated WAV chunk"))?;
+
+            match id {
+                b"fmt " if size >= 16 => {
+                    // WAVE_FORMAT_PCM, WAVE_FORMAT_IEEE_FLOAT or WAVE_FORMAT_EXTENSIBLE
+                    let tag = u16_at(offset + 8);
+                    let tag = if tag == 0xfffe && size >= 26 { u16_at(offset + 32) } else { tag };
+                    format = Some((tag, u16_at(offset + 10), u32_at(offset + 12), u16_at(offset + 22)));
+                }
+                b"data" => samples = Some(body),
+                _ => {}
+            }
+            // Chunks are padded to an even size
+            offset += 8 + size + (size & 1);
+        }
+
+        let (tag, nchannels, freq, bits) = format.ok_or_else(|| invalid("WAV file without a fmt chunk"))?;
+        let samples = samples.ok_or_else(|| invalid("WAV file without a data chunk"))?;
+        if nchannels == 0 || nchannels > u8::MAX as u16 {
+            return Err(invalid("Unsupported WAV channel count"));
+        }
+
+        let decode: fn(&[u8]) -> f32 = match (tag, bits) {
+            (1, 8) => |b| (b[0] as f32 - 128.0) / 128.0,
+            (1, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+            (1, 24) => |b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8388608.0,
+            (1, 32) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0,
+            (3, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            _ => return Err(invalid("Unsupported WAV sample format")),
+        };
+
+        Ok(Self {
+            freq,
+            nchannels: nchannels as u8,
+            samples: samples.chunks_exact((bits / 8) as usize).map(decode).collect(),
+            position: 0.0,
+            looping: false,
+        })
+    }
+
+    fn frames(&self) -> usize {
+        self.samples.len() / self.nchannels as usize
+    }
+}
+
+impl SampleGenerator for Wav {
+    fn fill(&mut self, freq: u32, nchannels: u8, samples: &mut [f32]) {
+        let frames = self.frames();
+        let step = self.freq as f64 / freq.max(1) as f64;
+
+        for frame in samples.chunks_mut(nchannels.max(1) as usize) {
+            if self.position as usize >= frames {
+                if !self.looping || frames == 0 {
+                    frame.fill(0.0);
+                    continue;
+                }
+                self.position = 0.0;
+            }
+
+            // Nearest frame, the extra channels repeat the last one of the file
+            let src = self.position as usize * self.nchannels as usize;
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample = self.samples[src + channel.min(self.nchannels as usize - 1)];
+            }
+            self.position += step;
+        }
+    }
+}
+
+/// `PcmSource` passing through raw PCM from a stream, e.g. a socket, that is already in the
+/// guest format. Whatever the stream doesn't have ready is filled with silence, so that a slow
+/// stream can't hold the guest back.
+#[derive(Debug)]
+pub struct StreamSource<R> {
+    stream: R,
+    voices: HashMap<u64, PcmInfo>,
+    eof: bool,
+    /// Start of a frame the stream didn't finish in time, sent with the next read
+    partial: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin + Send + Sync + 'static> StreamSource<R> {
+    pub fn new(stream: R) -> Self {
+        Self {
+            stream,
+            voices: HashMap::new(),
+            eof: false,
+            partial: Vec::new(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: AsyncRead + Unpin + Send + Sync + 'static> PcmSource for StreamSource<R> {
+    async fn init(&mut self, id: u64, info: PcmInfo) {
+        debug!("Audio in voice {} initialized: {:?}", id, info);
+        if !supported(&info) {
+            warn!("Unsupported audio in format: {:?}", info);
+            self.voices.remove(&id);
+            return;
+        }
+        self.voices.insert(id, info);
+    }
+
+    async fn fini(&mut self, id: u64) {
+        self.voices.remove(&id);
+    }
+
+    async fn set_enabled(&mut self, _id: u64, _enabled: bool) {}
+
+    async fn set_volume(&mut self, _id: u64, _volume: Volume) {}
+
+    async fn read(&mut self, id: u64, size: u64) -> Vec<u8> {
+        let Some(info) = self.voices.get(&id) else {
+            return Vec::new();
+        };
+        let bytes_per_frame = info.bytes_per_frame as usize;
+        let size = size as usize / bytes_per_frame * bytes_per_frame;
+
+        let mut data = vec![0; size];
+        let mut filled = self.partial.len().min(size);
+        data[..filled].copy_from_slice(&self.partial[..filled]);
+        self.partial.clear();
+        let deadline = Instant::now() + STREAM_READ_DEADLINE;
+        while !self.eof && filled < size {
+            let read = match tokio::time::timeout_at(deadline, self.stream.read(&mut data[filled..])).await {
+                Ok(read) => read,
+                Err(_) => break,
+            };
+            match read {
+                Ok(0) => self.eof = true,
+                Ok(n) => filled += n,
+                Err(e) => {
+                    warn!("Failed to read audio stream: {}", e);
+                    self.eof = true;
+                }
+            }
+        }
+
+        // Silence for the rest, keeping whole frames
+        let whole = filled / bytes_per_frame * bytes_per_frame;
+        self.partial.extend_from_slice(&data[whole..filled]);
+        let filled = whole;
+        let mut silence = Vec::new();
+        encode_sample(info, 0.0, &mut silence);
+        for sample in data[filled..].chunks_mut(silence.len().max(1)) {
+            sample.copy_from_slice(&silence[..sample.len()]);
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(bits: u8, is_signed: bool, is_float: bool, be: bool) -> PcmInfo {
+        PcmInfo {
+            bits,
+            is_signed,
+            is_float,
+            freq: 8000,
+            nchannels: 2,
+            bytes_per_frame: 2 * bits as u32 / 8,
+            bytes_per_second: 8000 * 2 * bits as u32 / 8,
+            be,
+        }
+    }
+
+    fn encode(info: &PcmInfo, value: f32) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode_sample(info, value, &mut out);
+        out
+    }
+
+    #[test]
+    fn encodes_sample_formats() {
+        assert_eq!(encode(&info(16, true, false, false), 1.0), vec![0xff, 0x7f]);
+        assert_eq!(encode(&info(16, true, false, false), -1.0), vec![0x01, 0x80]);
+        assert_eq!(encode(&info(16, true, false, true), 1.0), vec![0x7f, 0xff]);
+        assert_eq!(encode(&info(8, false, false, false), 0.0), vec![0x80]);
+        assert_eq!(encode(&info(16, false, false, false), 0.0), vec![0x00, 0x80]);
+        assert_eq!(encode(&info(32, true, true, false), 0.5), 0.5f32.to_le_bytes().to_vec());
+        assert_eq!(encode(&info(32, true, false, false), 2.0), i32::MAX.to_le_bytes().to_vec());
+    }
+
+    #[test]
+    fn parses_wav_files() {
+        let mut file = Vec::new();
+        file.extend_from_slice(b"RIFF");
+        file.extend_from_slice(&36u32.to_le_bytes());
+        file.extend_from_slice(b"WAVEfmt ");
+        file.extend_from_slice(&16u32.to_le_bytes());
+        file.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        file.extend_from_slice(&1u16.to_le_bytes()); // mono
+        file.extend_from_slice(&4000u32.to_le_bytes());
+        file.extend_from_slice(&8000u32.to_le_bytes());
+        file.extend_from_slice(&2u16.to_le_bytes());
+        file.extend_from_slice(&16u16.to_le_bytes());
+        file.extend_from_slice(b"data");
+        file.extend_from_slice(&4u32.to_le_bytes());
+        file.extend_from_slice(&i16::MIN.to_le_bytes());
+        file.extend_from_slice(&16384i16.to_le_bytes());
+
+        let mut wav = Wav::parse(&file).unwrap();
+        assert_eq!((wav.freq, wav.nchannels), (4000, 1));
+
+        // Twice the rate and stereo, then silence at the end
+        let mut samples = [1.0; 10];
+        wav.fill(8000, 2, &mut samples);
+        assert_eq!(samples, [-1.0, -1.0, -1.0, -1.0, 0.5, 0.5, 0.5, 0.5, 0.0, 0.0]);
+
+        assert!(Wav::parse(b"RIFF\0\0\0\0AVI ").is_err());
+    }
+
+    #[tokio::test]
+    async fn generator_source_applies_format_and_volume() {
+        let mut source = GeneratorSource::new(Sine::new(1000.0, 1.0));
+        source.init(1, info(16, true, false, false)).await;
+
+        // Disabled voices are silent
+        assert_eq!(source.read(1, 8).await, vec![0; 8]);
+
+        source.set_enabled(1, true).await;
+        let data = source.read(1, 4 * 100 + 3).await;
+        assert_eq!(data.len(), 4 * 100);
+        assert!(data.iter().any(|b| *b != 0));
+
+        source.set_volume(1, Volume { mute: true, volume: vec![255, 255] }).await;
+        assert_eq!(source.read(1, 400).await, vec![0; 400]);
+
+        assert!(source.read(2, 400).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stream_source_pads_with_silence() {
+        let stream: &[u8] = &[1, 2, 3, 4, 5];
+        let mut source = StreamSource::new(stream);
+        source.init(1, info(8, false, false, false)).await;
+
+        assert_eq!(source.read(1, 8).await, vec![1, 2, 3, 4, 0x80, 0x80, 0x80, 0x80]);
+    }
+
+    #[tokio::test]
+    async fn stream_source_does_not_wait_for_a_slow_stream() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        tokio::io::AsyncWriteExt::write_all(&mut writer, &[1, 2, 3]).await.unwrap();
+        let mut source = StreamSource::new(reader);
+        source.init(1, info(8, false, false, false)).await;
+
+        // The writer is still open but has nothing more to give, the odd byte waits for the
+        // rest of its frame
+        assert_eq!(source.read(1, 4).await, vec![1, 2, 0x80, 0x80]);
+        tokio::io::AsyncWriteExt::write_all(&mut writer, &[4]).await.unwrap();
+        assert_eq!(source.read(1, 4).await, vec![3, 4, 0x80, 0x80]);
+    }
+
+    #[tokio::test]
+    async fn unsupported_formats_are_rejected() {
+        let mut source = StreamSource::new(&[0u8; 8][..]);
+        source.init(1, info(0, true, false, false)).await;
+        source.init(2, info(12, true, false, false)).await;
+        source.init(3, info(16, true, true, false)).await;
+        for id in 1..=3 {
+            assert!(source.read(id, 8).await.is_empty());
+        }
+
+        let mut source = GeneratorSource::new(Sine::new(1000.0, 1.0));
+        source.init(1, info(0, true, false, false)).await;
+        assert!(source.read(1, 8).await.is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::convert::TryFrom;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use zbus::{dbus_proxy, zvariant::ObjectPath, Connection};
+use zbus::zvariant::Fd;
+use tracing::info;
+use crate::display::utils::prepare_uds_pass;
+use crate::display::vm::DEFAULT_SERVICE;
+use crate::display::error::DisplayError;
+
+#[dbus_proxy(default_service = "org.qemu", interface = "org.qemu.Display1.Chardev")]
+pub trait Chardev {
+    /// Register method, connects the chardev frontend to the given socket
+    fn register(&self, stream: Fd) -> zbus::Result<()>;
+
+    /// SendBreak method
+    fn send_break(&self) -> zbus::Result<()>;
+
+    #[dbus_proxy(property)]
+    fn name(&self) -> zbus::Result<String>;
+
+    #[dbus_proxy(property, name = "FEOpened")]
+    fn fe_opened(&self) -> zbus::Result<bool>;
+
+    #[dbus_proxy(property)]
+    fn echo(&self) -> zbus::Result<bool>;
+
+    #[dbus_proxy(property)]
+    fn owner(&self) -> zbus::Result<String>;
+}
+
+/// A QEMU chardev exported on D-Bus, like a serial port or the HMP monitor.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct Chardev {
+    #[derivative(Debug = "ignore")]
+    pub proxy: ChardevProxy<'static>,
+}
+
+impl Chardev {
+    /// Opens `/org/qemu/Display1/Chardev_<name>`, the name being the chardev id.
+    pub async fn with_connection(connection: &Connection, name: &str) -> Result<Self, DisplayError> {
+        Self::with_service(connection, DEFAULT_SERVICE, name).await
+    }
+
+    pub async fn with_service(connection: &Connection, service: &str, name: &str) -> Result<Self, DisplayError> {
+        let obj_path = ObjectPath::try_from(format!("/org/qemu/Display1/Chardev_{}", name))?;
+        let proxy = ChardevProxy::builder(connection)
+            .destination(service.to_string())?
+            .path(&obj_path)?
+            .build()
+            .await?;
+
+        Ok(Self { proxy })
+    }
+
+    /// Connects to the chardev, the returned stream reads its output and writes its input.
+    ///
+    /// Registering again replaces the previous stream.
+    pub async fn open(&self) -> Result<tokio::net::UnixStream, DisplayError> {
+        let (p0, p1) = UnixStream::pair()?;
+        self.proxy.register(prepare_uds_pass(&p0)?).await?;
+
+        p1.set_nonblocking(true)?;
+        Ok(tokio::net::UnixStream::from_std(p1)?)
+    }
+
+    /// Bridges the chardev to a new pseudo-terminal and returns the path of its slave side,
+    /// e.g. `/dev/pts/3`, for `screen` or `minicom` to attach to.
+    pub async fn pty_bridge(&self) -> Result<PathBuf, DisplayError> {
+        let (master, slave, path) = open_pty()?;
+        let master = PtyFile::new(master)?;
+        let stream = self.open().await?;
+
+        tokio::spawn(bridge(master, slave, stream));
+
+        Ok(path)
+    }
+}
+
+/// Copies between the pty and the chardev stream until either side closes, then closes both.
+async fn bridge(master: PtyFile, slave: File, mut stream: tokio::net::UnixStream) {
+    // Keeps the terminal up while nothing is attached, reads would fail with EIO otherwise.
+    // The master never sees an end of file because of it, so the stream alone ends the bridge.
+    let _slave = slave;
+    let (mut pty_reader, mut pty_writer) = tokio::io::split(master);
+    let (mut stream_reader, mut stream_writer) = stream.split();
+
+    let result = tokio::select! {
+        result = tokio::io::copy(&mut pty_reader, &mut stream_writer) => result,
+        result = tokio::io::copy(&mut stream_reader, &mut pty_writer) => result,
+    };
+    match result {
+        Ok(_) => info!("Chardev bridge closed"),
+        Err(e) => info!("Chardev bridge closed: {}", e),
+    }
+}
+
+/// A pty end read and written as the runtime sees it ready.
+///
+/// `tokio::fs::File` would run every read on a blocking thread, and a read pending there
+/// holds back the writes to the same file, so that one direction stalls the other.
+struct PtyFile(AsyncFd<File>);
+
+impl PtyFile {
+    fn new(file: File) -> io::Result<Self> {
+        let fd = file.as_raw_fd();
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(Self(AsyncFd::new(file)?))
+    }
+}
+
+impl AsyncRead for PtyFile {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|file| file.get_ref().read(unfilled)) {
+                Ok(result) => {
+                    buf.advance(result?);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for PtyFile {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            match guard.try_io(|file| file.get_ref().write(data)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Opens a pseudo-terminal in raw mode, so the guest sees the bytes as typed.
+fn open_pty() -> std::io::Result<(File, File, PathBuf)> {
+    unsafe {
+        let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC);
+        if master < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let master = File::from_raw_fd(master);
+        let fd = master.as_raw_fd();
+
+        if libc::grantpt(fd) < 0 || libc::unlockpt(fd) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut name = [0 as libc::c_char; 64];
+        if libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let path = PathBuf::from(CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned());
+
+        let slave = File::options()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+        let slave_fd = slave.as_raw_fd();
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(slave_fd, &mut termios) == 0 {
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(slave_fd, libc::TCSANOW, &termios);
+        }
+
+        Ok((master, slave, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn pty_bridge_copies_both_ways() {
+        let (master, slave, path) = open_pty().unwrap();
+        let (chardev, qemu) = UnixStream::pair().unwrap();
+        chardev.set_nonblocking(true).unwrap();
+        qemu.set_nonblocking(true).unwrap();
+        let mut qemu = tokio::net::UnixStream::from_std(qemu).unwrap();
+        let task = tokio::spawn(bridge(
+            PtyFile::new(master).unwrap(),
+            slave,
+            tokio::net::UnixStream::from_std(chardev).unwrap(),
+        ));
+
+        // A terminal attached to the slave side
+        let terminal = File::options().read(true).write(true).custom_flags(libc::O_NOCTTY).open(&path).unwrap();
+        let mut terminal = PtyFile::new(terminal).unwrap();
+        let mut buf = [0; 5];
+
+        // Output of the guest shows on the terminal while nothing is typed
+        qemu.write_all(b"login").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), terminal.read_exact(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf, b"login");
+
+        terminal.write_all(b"root\n").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), qemu.read_exact(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf, b"root\n");
+
+        // QEMU closing the chardev ends the bridge, even with the terminal still attached
+        drop(qemu);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,452 @@
+use std::sync::{Arc, Mutex};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use zbus::{dbus_interface, dbus_proxy, Connection};
+use tracing::{debug, instrument, warn};
+use zvariant::Type;
+use crate::display::error::DisplayError;
+
+/// Mime type of UTF-8 text, as used by the QEMU vdagent.
+pub const MIME_TEXT: &str = "text/plain;charset=utf-8";
+pub const MIME_PNG: &str = "image/png";
+
+const CLIPBOARD_PATH: &str = "/org/qemu/Display1/Clipboard";
+
+#[repr(u32)]
+#[derive(Deserialize_repr, Serialize_repr, Type, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ClipboardSelection {
+    Clipboard,
+    Primary,
+    Secondary,
+}
+
+#[dbus_proxy(
+    default_service = "org.qemu",
+    default_path = "/org/qemu/Display1/Clipboard",
+    interface = "org.qemu.Display1.Clipboard"
+)]
+pub trait Clipboard {
+    /// Register method
+    fn register(&self) -> zbus::Result<()>;
+
+    /// Unregister method
+    fn unregister(&self) -> zbus::Result<()>;
+
+    /// Grab method
+    fn grab(&self, selection: ClipboardSelection, serial: u32, mimes: &[&str]) -> zbus::Result<()>;
+
+    /// Release method
+    fn release(&self, selection: ClipboardSelection) -> zbus::Result<()>;
+
+    /// Request method, returns the mime type picked and the data
+    fn request(&self, selection: ClipboardSelection, mimes: &[&str]) -> zbus::Result<(String, Vec<u8>)>;
+}
+
+/// The clipboard of the host the viewer runs on.
+pub trait HostClipboard: Send + 'static {
+    /// Mime types of the current contents, most preferred first.
+    fn mimes(&self) -> Vec<String>;
+
+    fn get(&self, mime: &str) -> Option<Vec<u8>>;
+
+    /// Replaces the contents with `data`.
+    fn set(&mut self, mime: &str, data: Vec<u8>);
+}
+
+/// Host clipboard kept in memory, for headless viewers and tests.
+#[derive(Debug, Default)]
+pub struct MemoryClipboard {
+    contents: Option<(String, Vec<u8>)>,
+}
+
+impl HostClipboard for MemoryClipboard {
+    fn mimes(&self) -> Vec<String> {
+        self.contents.iter().map(|(mime, _)| mime.clone()).collect()
+    }
+
+    fn get(&self, mime: &str) -> Option<Vec<u8>> {
+        match &self.contents {
+            Some((m, data)) if m == mime => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, mime: &str, data: Vec<u8>) {
+        self.contents = Some((mime.to_string(), data));
+    }
+}
+
+/// Text clipboard of the desktop, through arboard.
+///
+/// The desktop doesn't say when its clipboard changes, `changed` has to be polled.
+#[cfg(feature = "host-clipboard")]
+pub struct ArboardClipboard {
+    clipboard: Mutex<arboard::Clipboard>,
+    /// Text last seen on or copied to the desktop clipboard
+    last: Option<String>,
+}
+
+#[cfg(feature = "host-clipboard")]
+impl ArboardClipboard {
+    pub fn new() -> Result<Self, DisplayError> {
+        let clipboard = arboard::Clipboard::new().map_err(|e| DisplayError::Io(std::io::Error::other(e)))?;
+
+        Ok(Self {
+            clipboard: Mutex::new(clipboard),
+            last: None,
+        })
+    }
+
+    fn text(&self) -> Option<String> {
+        self.clipboard.lock().unwrap().get_text().ok()
+    }
+
+    /// Whether something else than what the guest sent was copied on the desktop since the
+    /// last call.
+    pub fn changed(&mut self) -> bool {
+        let text = self.text();
+        if text == self.last {
+            return false;
+        }
+        self.last = text;
+        self.last.is_some()
+    }
+}
+
+#[cfg(feature = "host-clipboard")]
+impl HostClipboard for ArboardClipboard {
+    fn mimes(&self) -> Vec<String> {
+        self.text().map(|_| MIME_TEXT.to_string()).into_iter().collect()
+    }
+
+    fn get(&self, mime: &str) -> Option<Vec<u8>> {
+        if mime != MIME_TEXT {
+            return None;
+        }
+        self.text().map(String::into_bytes)
+    }
+
+    fn set(&mut self, mime: &str, data: Vec<u8>) {
+        if mime != MIME_TEXT {
+            return;
+        }
+        let text = String::from_utf8_lossy(&data).into_owned();
+        if let Err(e) = self.clipboard.lock().unwrap().set_text(text.clone()) {
+            warn!("Failed to set the host clipboard: {}", e);
+            return;
+        }
+        // Not a change to send back to the guest
+        self.last = Some(text);
+    }
+}
+
+/// Ownership of the `Clipboard` selection between the host and the guest, independent of D-Bus.
+///
+/// Whoever grabs last owns the selection, and grabs carry a serial so that a stale grab from
+/// the guest can't take it back from a newer host one.
+#[derive(Debug)]
+pub struct ClipboardSync<C> {
+    host: C,
+    images: bool,
+    serial: u32,
+    /// Mime types the guest offers while it owns the selection
+    guest: Option<Vec<String>>,
+}
+
+impl<C: HostClipboard> ClipboardSync<C> {
+    /// Syncs text, and PNG images too when `images` is set.
+    pub fn new(host: C, images: bool) -> Self {
+        Self {
+            host,
+            images,
+            serial: 0,
+            guest: None,
+        }
+    }
+
+    pub fn host(&self) -> &C {
+        &self.host
+    }
+
+    pub fn host_mut(&mut self) -> &mut C {
+        &mut self.host
+    }
+
+    fn supported(&self, mime: &str) -> bool {
+        mime == MIME_TEXT || (self.images && mime == MIME_PNG)
+    }
+
+    /// QEMU (re)started and forgot the previous serials.
+    pub fn reset(&mut self) {
+        self.serial = 0;
+        self.guest = None;
+    }
+
+    /// The guest took the selection. Returns the mime types to request from it, if any of
+    /// them can be synced.
+    pub fn guest_grab(&mut self, serial: u32, mimes: Vec<String>) -> Option<Vec<String>> {
+        if serial < self.serial {
+            debug!("Ignoring stale clipboard grab {} < {}", serial, self.serial);
+            return None;
+        }
+        self.serial = serial;
+
+        let wanted: Vec<String> = mimes.iter().filter(|mime| self.supported(mime)).cloned().collect();
+        self.guest = Some(mimes);
+        (!wanted.is_empty()).then_some(wanted)
+    }
+
+    pub fn guest_release(&mut self) {
+        self.guest = None;
+    }
+
+    /// Data the guest sent for its grab, copied to the host unless the host grabbed since.
+    pub fn guest_data(&mut self, mime: &str, data: Vec<u8>) {
+        if self.guest.is_none() || !self.supported(mime) {
+            return;
+        }
+        self.host.set(mime, data);
+    }
+
+    /// The guest asks for the host contents in one of `mimes`.
+    pub fn guest_request(&self, mimes: &[String]) -> Option<(String, Vec<u8>)> {
+        if self.guest.is_some() {
+            return None;
+        }
+
+        mimes
+            .iter()
+            .filter(|mime| self.supported(mime))
+            .find_map(|mime| Some((mime.clone(), self.host.get(mime)?)))
+    }
+
+    /// The host contents changed. Returns the serial and mime types to grab the guest
+    /// selection with, or `None` if there is nothing to sync.
+    pub fn host_grab(&mut self) -> Option<(u32, Vec<String>)> {
+        let mimes: Vec<String> = self.host.mimes().into_iter().filter(|mime| self.supported(mime)).collect();
+        if mimes.is_empty() {
+            return None;
+        }
+
+        self.serial = self.serial.wrapping_add(1);
+        self.guest = None;
+        Some((self.serial, mimes))
+    }
+}
+
+/// Client side `org.qemu.Display1.Clipboard` object, which QEMU calls for the guest clipboard.
+pub(crate) struct ClipboardListener<C> {
+    sync: Arc<Mutex<ClipboardSync<C>>>,
+    proxy: ClipboardProxy<'static>,
+}
+
+#[dbus_interface(name = "org.qemu.Display1.Clipboard")]
+impl<C: HostClipboard> ClipboardListener<C> {
+    #[instrument(level = "debug", skip(self))]
+    async fn register(&self) {
+        self.sync.lock().unwrap().reset();
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn unregister(&self) {
+        self.sync.lock().unwrap().guest_release();
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn grab(&self, selection: ClipboardSelection, serial: u32, mimes: Vec<String>) {
+        if selection != ClipboardSelection::Clipboard {
+            return;
+        }
+        let Some(wanted) = self.sync.lock().unwrap().guest_grab(serial, mimes) else {
+            return;
+        };
+
+        // QEMU may have to ask the guest agent first, don't hold the Grab call meanwhile
+        let sync = Arc::clone(&self.sync);
+        let proxy = self.proxy.clone();
+        tokio::spawn(async move {
+            let wanted: Vec<&str> = wanted.iter().map(String::as_str).collect();
+            match proxy.request(ClipboardSelection::Clipboard, &wanted).await {
+                Ok((mime, data)) => sync.lock().unwrap().guest_data(&mime, data),
+                Err(e) => warn!("Failed to get the guest clipboard: {}", e),
+            }
+        });
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn release(&self, selection: ClipboardSelection) {
+        if selection == ClipboardSelection::Clipboard {
+            self.sync.lock().unwrap().guest_release();
+        }
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn request(
+        &self,
+        selection: ClipboardSelection,
+        mimes: Vec<String>,
+    ) -> zbus::fdo::Result<(String, Vec<u8>)> {
+        if selection != ClipboardSelection::Clipboard {
+            return Err(zbus::fdo::Error::NotSupported("Only the clipboard selection is shared".into()));
+        }
+
+        self.sync
+            .lock()
+            .unwrap()
+            .guest_request(&mimes)
+            .ok_or_else(|| zbus::fdo::Error::Failed("No clipboard data in the requested types".into()))
+    }
+}
+
+/// Clipboard shared between the host and the guest, registered with QEMU on the bus.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct SharedClipboard<C> {
+    #[derivative(Debug = "ignore")]
+    pub proxy: ClipboardProxy<'static>,
+    #[derivative(Debug = "ignore")]
+    sync: Arc<Mutex<ClipboardSync<C>>>,
+    #[derivative(Debug = "ignore")]
+    connection: Connection,
+}
+
+impl<C: HostClipboard> SharedClipboard<C> {
+    /// Serves the clipboard object on `connection` and registers it with the QEMU owning
+    /// `service`.
+    pub async fn register(
+        connection: &Connection,
+        service: &str,
+        host: C,
+        images: bool,
+    ) -> Result<Self, DisplayError> {
+        let proxy = ClipboardProxy::builder(connection)
+            .destination(service.to_string())?
+            .build()
+            .await?;
+        let sync = Arc::new(Mutex::new(ClipboardSync::new(host, images)));
+
+        connection
+            .object_server()
+            .at(
+                CLIPBOARD_PATH,
+                ClipboardListener {
+                    sync: Arc::clone(&sync),
+                    proxy: proxy.clone(),
+                },
+            )
+            .await?;
+        proxy.register().await?;
+
+        Ok(Self {
+            proxy,
+            sync,
+            connection: connection.clone(),
+        })
+    }
+
+    /// Access to the host clipboard, e.g. to read what the guest copied.
+    pub fn with_host<R>(&self, f: impl FnOnce(&mut C) -> R) -> R {
+        f(self.sync.lock().unwrap().host_mut())
+    }
+
+    /// Tells the guest the host clipboard changed, call it after the host contents were set.
+    pub async fn host_changed(&self) -> zbus::Result<()> {
+        let Some((serial, mimes)) = self.sync.lock().unwrap().host_grab() else {
+            return Ok(());
+        };
+        let mimes: Vec<&str> = mimes.iter().map(String::as_str).collect();
+
+        self.proxy.grab(ClipboardSelection::Clipboard, serial, &mimes).await
+    }
+
+    pub async fn unregister(&self) -> Result<(), DisplayError> {
+        self.proxy.unregister().await?;
+        self.connection
+            .object_server()
+            .remove::<ClipboardListener<C>, _>(CLIPBOARD_PATH)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Vec<u8> {
+        s.as_bytes().to_vec()
+    }
+
+    #[test]
+    fn guest_copy_reaches_the_host() {
+        let mut sync = ClipboardSync::new(MemoryClipboard::default(), false);
+
+        let wanted = sync.guest_grab(1, vec![MIME_PNG.to_string(), MIME_TEXT.to_string()]);
+        assert_eq!(wanted, Some(vec![MIME_TEXT.to_string()]));
+
+        sync.guest_data(MIME_TEXT, text("from the guest"));
+        assert_eq!(sync.host().get(MIME_TEXT), Some(text("from the guest")));
+    }
+
+    #[test]
+    fn host_copy_is_served_to_the_guest() {
+        let mut sync = ClipboardSync::new(MemoryClipboard::default(), false);
+        sync.host_mut().set(MIME_TEXT, text("from the host"));
+
+        let (serial, mimes) = sync.host_grab().unwrap();
+        assert_eq!(serial, 1);
+        assert_eq!(mimes, vec![MIME_TEXT.to_string()]);
+
+        let request = sync.guest_request(&["text/html".to_string(), MIME_TEXT.to_string()]);
+        assert_eq!(request, Some((MIME_TEXT.to_string(), text("from the host"))));
+    }
+
+    #[test]
+    fn images_are_opt_in() {
+        let mut sync = ClipboardSync::new(MemoryClipboard::default(), false);
+        assert_eq!(sync.guest_grab(1, vec![MIME_PNG.to_string()]), None);
+        sync.host_mut().set(MIME_PNG, vec![0x89, b'P', b'N', b'G']);
+        assert_eq!(sync.host_grab(), None);
+
+        let mut sync = ClipboardSync::new(MemoryClipboard::default(), true);
+        assert_eq!(sync.guest_grab(1, vec![MIME_PNG.to_string()]), Some(vec![MIME_PNG.to_string()]));
+        sync.guest_data(MIME_PNG, vec![0x89, b'P', b'N', b'G']);
+        assert_eq!(sync.host().mimes(), vec![MIME_PNG.to_string()]);
+    }
+
+    #[test]
+    fn stale_guest_grab_is_ignored() {
+        let mut sync = ClipboardSync::new(MemoryClipboard::default(), false);
+        sync.guest_grab(5, vec![MIME_TEXT.to_string()]);
+        sync.host_mut().set(MIME_TEXT, text("host"));
+        let (serial, _) = sync.host_grab().unwrap();
+        assert_eq!(serial, 6);
+
+        // A grab the guest sent before seeing ours
+        assert_eq!(sync.guest_grab(5, vec![MIME_TEXT.to_string()]), None);
+        sync.guest_data(MIME_TEXT, text("guest"));
+        assert_eq!(sync.host().get(MIME_TEXT), Some(text("host")));
+    }
+
+    #[test]
+    fn guest_owned_selection_is_not_served_back() {
+        let mut sync = ClipboardSync::new(MemoryClipboard::default(), false);
+        sync.host_mut().set(MIME_TEXT, text("old host text"));
+        sync.guest_grab(1, vec![MIME_TEXT.to_string()]);
+        assert_eq!(sync.guest_request(&[MIME_TEXT.to_string()]), None);
+
+        sync.guest_release();
+        assert!(sync.guest_request(&[MIME_TEXT.to_string()]).is_some());
+    }
+
+    #[test]
+    fn data_after_a_host_grab_is_dropped() {
+        let mut sync = ClipboardSync::new(MemoryClipboard::default(), false);
+        sync.guest_grab(1, vec![MIME_TEXT.to_string()]);
+        sync.host_mut().set(MIME_TEXT, text("host"));
+        sync.host_grab();
+
+        // The reply to the request sent for the guest grab arrives late
+        sync.guest_data(MIME_TEXT, text("guest"));
+        assert_eq!(sync.host().get(MIME_TEXT), Some(text("host")));
+    }
+}
@@ -0,0 +1,167 @@
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use zbus::{Connection, ConnectionBuilder};
+use crate::display::qmp::add_dbus_display_client;
+use crate::display::error::DisplayError;
+
+/// Where to find the D-Bus display of QEMU.
+#[derive(Debug, Default)]
+pub enum ConnectOptions {
+    /// `-display dbus` on the session bus
+    #[default]
+    Session,
+    /// `-display dbus` on the system bus
+    System,
+    /// A bus at a D-Bus address, e.g. `unix:path=/run/libvirt/qemu/dbus/vm-system.sock`
+    Address(String),
+    /// A peer-to-peer socket to QEMU at this path, no bus involved
+    P2p(PathBuf),
+    /// An already connected peer-to-peer socket, closed with the connection
+    Fd(OwnedFd),
+    /// `-display dbus,p2p=yes`, a socket is handed to QEMU through the QMP socket at this path
+    Qmp(PathBuf),
+}
+
+impl ConnectOptions {
+    /// Another copy of the options, to connect again, e.g. after QEMU restarted.
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(match self {
+            ConnectOptions::Session => ConnectOptions::Session,
+            ConnectOptions::System => ConnectOptions::System,
+            ConnectOptions::Address(address) => ConnectOptions::Address(address.clone()),
+            ConnectOptions::P2p(path) => ConnectOptions::P2p(path.clone()),
+            ConnectOptions::Fd(fd) => ConnectOptions::Fd(fd.try_clone()?),
+            ConnectOptions::Qmp(path) => ConnectOptions::Qmp(path.clone()),
+        })
+    }
+
+    /// Whether `try_clone` gives options to connect again, which isn't the case of an fd
+    /// once its peer went away.
+    pub fn reconnects(&self) -> bool {
+        !matches!(self, ConnectOptions::Fd(_))
+    }
+
+    pub async fn connect(self) -> Result<Connection, DisplayError> {
+        let connection = match self {
+            ConnectOptions::Session => Connection::session().await?,
+            ConnectOptions::System => Connection::system().await?,
+            ConnectOptions::Address(address) => ConnectionBuilder::address(address.as_str())?.build().await?,
+            ConnectOptions::P2p(path) => {
+                let stream = UnixStream::connect(path)?;
+                ConnectionBuilder::unix_stream(stream).p2p().build().await?
+            }
+            ConnectOptions::Fd(fd) => {
+                let stream = UnixStream::from(fd);
+                ConnectionBuilder::unix_stream(stream).p2p().build().await?
+            }
+            ConnectOptions::Qmp(path) => {
+                let stream = add_dbus_display_client(path).await?;
+                ConnectionBuilder::unix_stream(stream).p2p().build().await?
+            }
+        };
+
+        Ok(connection)
+    }
+}
+
+impl FromRawFd for ConnectOptions {
+    /// Connects through a peer-to-peer socket handed to us, e.g. inherited from the parent
+    /// process.
+    ///
+    /// # Safety
+    ///
+    /// `fd` has to be an open socket that nothing else owns, it is closed with the connection.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        ConnectOptions::Fd(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+/// A connection given on the command line.
+///
+/// An `fd:<number>` is only parsed, taking ownership of the fd is left to the caller with
+/// `ConnectOptions::from_raw_fd`, as only the caller knows it was really handed over.
+#[derive(Debug)]
+pub enum ConnectSpec {
+    Options(ConnectOptions),
+    Fd(RawFd),
+}
+
+/// Parses `session`, `system`, `p2p:<path>`, `fd:<number>`, `qmp:<path>` or a D-Bus address.
+impl FromStr for ConnectSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let options = match s {
+            "session" => ConnectOptions::Session,
+            "system" => ConnectOptions::System,
+            _ => {
+                if let Some(path) = s.strip_prefix("p2p:") {
+                    ConnectOptions::P2p(PathBuf::from(path))
+                } else if let Some(path) = s.strip_prefix("qmp:") {
+                    ConnectOptions::Qmp(PathBuf::from(path))
+                } else if let Some(fd) = s.strip_prefix("fd:") {
+                    let fd: RawFd = fd.parse().map_err(|_| format!("Invalid fd: {}", fd))?;
+                    if fd < 0 {
+                        return Err(format!("Invalid fd: {}", fd));
+                    }
+                    return Ok(ConnectSpec::Fd(fd));
+                } else if s.contains(':') {
+                    ConnectOptions::Address(s.to_string())
+                } else {
+                    return Err(format!("Unknown connection: {}", s));
+                }
+            }
+        };
+
+        Ok(ConnectSpec::Options(options))
+    }
+}
+
+/// Parses the same as `ConnectSpec` but `fd:<number>`, which needs `ConnectOptions::from_raw_fd`.
+impl FromStr for ConnectOptions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse()? {
+            ConnectSpec::Options(options) => Ok(options),
+            ConnectSpec::Fd(_) => Err(format!("{} has to be parsed as a ConnectSpec", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn parses_connections() {
+        assert!(matches!("session".parse(), Ok(ConnectOptions::Session)));
+        assert!(matches!("system".parse(), Ok(ConnectOptions::System)));
+        assert!(matches!("p2p:/tmp/qemu.sock".parse(), Ok(ConnectOptions::P2p(path)) if path == Path::new("/tmp/qemu.sock")));
+        assert!(matches!("qmp:/tmp/qmp.sock".parse(), Ok(ConnectOptions::Qmp(path)) if path == Path::new("/tmp/qmp.sock")));
+        assert!(matches!(
+            "unix:path=/run/dbus.sock".parse(),
+            Ok(ConnectOptions::Address(address)) if address == "unix:path=/run/dbus.sock"
+        ));
+        assert!("vnc".parse::<ConnectOptions>().is_err());
+    }
+
+    #[test]
+    fn fds_are_only_parsed() {
+        assert!(matches!("fd:3".parse(), Ok(ConnectSpec::Fd(3))));
+        assert!(matches!("session".parse(), Ok(ConnectSpec::Options(ConnectOptions::Session))));
+        assert!("fd:-1".parse::<ConnectSpec>().is_err());
+        assert!("fd:stdin".parse::<ConnectSpec>().is_err());
+        assert!("fd:3".parse::<ConnectOptions>().is_err());
+    }
+
+    #[test]
+    fn owned_fds_are_cloned() {
+        let (socket, _peer) = UnixStream::pair().unwrap();
+        let options = unsafe { ConnectOptions::from_raw_fd(std::os::unix::io::IntoRawFd::into_raw_fd(socket)) };
+        assert!(matches!(options.try_clone(), Ok(ConnectOptions::Fd(_))));
+    }
+}
@@ -4,10 +4,21 @@ use zbus::zvariant::Fd;
 use zbus::{dbus_proxy, zvariant::ObjectPath, Connection};
 use std::os::unix::net::UnixStream;
 use std::{convert::TryFrom};
-use tokio::sync::RwLock;
+use std::path::Path;
+use std::sync::Arc;
+use futures_util::StreamExt;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, info};
+#[cfg(unix)]
+use crate::display::console_listenner::ConsoleListenerMap;
+use crate::display::connect::ConnectOptions;
+use crate::display::console_events::{watch_connection, ConsoleEvents, EventSender, OverflowPolicy, DEFAULT_EVENT_CAPACITY};
 use crate::display::console_listenner::{ConsoleListener, ConsoleListenerHandler};
+use crate::display::vm::{Vm, DEFAULT_SERVICE};
 use crate::display::keyboard::KeyboardProxy;
 use crate::display::mouse::MouseProxy;
+use crate::display::touch::MultiTouchProxy;
+use crate::display::error::DisplayError;
 
 #[dbus_proxy(default_service = "org.qemu",  interface = "org.qemu.Display1.Console")]
 pub trait Console {
@@ -52,58 +63,120 @@ pub struct Console {
     pub keyboard: KeyboardProxy<'static>,
     #[derivative(Debug = "ignore")]
     pub mouse: MouseProxy<'static>,
+    #[derivative(Debug = "ignore")]
+    pub multi_touch: MultiTouchProxy<'static>,
     listener: RwLock<Option<Connection>>,
 }
 
 impl Console {
-    pub async fn new(idx: u32) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+    pub async fn new(idx: u32) -> Result<Self, DisplayError> {
+        Self::connect(ConnectOptions::Session, idx).await
+    }
+
+    pub async fn connect(options: ConnectOptions, idx: u32) -> Result<Self, DisplayError> {
+        let connection = options.connect().await?;
+        Self::with_connection(&connection, idx).await
+    }
+
+    /// Attaches to a VM started with `-display dbus,p2p=yes` through its QMP socket and
+    /// returns its graphical console.
+    pub async fn attach_via_qmp(path: impl AsRef<Path>) -> Result<Self, DisplayError> {
+        let vm = Vm::connect(ConnectOptions::Qmp(path.as_ref().to_path_buf())).await?;
+        vm.graphic_console().await
+    }
+
+    pub async fn with_connection(connection: &Connection, idx: u32) -> Result<Self, DisplayError> {
+        Self::with_service(connection, DEFAULT_SERVICE, idx).await
+    }
+
+    /// Like `with_connection`, for a VM owning `service` instead of `org.qemu`.
+    pub async fn with_service(connection: &Connection, service: &str, idx: u32) -> Result<Self, DisplayError> {
         let obj_path = ObjectPath::try_from(format!("/org/qemu/Display1/Console_{}", idx))?;
-        let connection = Connection::session().await?;
 
-        let proxy = ConsoleProxy::builder(&connection).path(&obj_path)?.build().await?;
-        let keyboard = KeyboardProxy::builder(&connection)
+        let proxy = ConsoleProxy::builder(connection)
+            .destination(service.to_string())?
+            .path(&obj_path)?
+            .build()
+            .await?;
+        let keyboard = KeyboardProxy::builder(connection)
+            .destination(service.to_string())?
+            .path(&obj_path)?
+            .build()
+            .await?;
+        let mouse = MouseProxy::builder(connection)
+            .destination(service.to_string())?
+            .path(&obj_path)?
+            .build()
+            .await?;
+        let multi_touch = MultiTouchProxy::builder(connection)
+            .destination(service.to_string())?
             .path(&obj_path)?
             .build()
             .await?;
-        let mouse = MouseProxy::builder(&connection).path(&obj_path)?.build().await?;
 
         Ok(Self {
             proxy,
             keyboard,
             mouse,
+            multi_touch,
             listener: RwLock::new(None),
         })
     }
 
-    pub async fn register_listener<H: ConsoleListenerHandler>(&self, handler: H) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        println!("Preparing UnixStream pair");
+    pub async fn register_listener<H: ConsoleListenerHandler>(&self, handler: H) -> Result<(), DisplayError> {
+        self.register_shared_listener(Arc::new(Mutex::new(handler))).await
+    }
+
+    /// Like `register_listener`, keeping the handler usable for another registration, e.g.
+    /// after reconnecting to a restarted QEMU.
+    pub async fn register_shared_listener<H: ConsoleListenerHandler>(&self, handler: Arc<Mutex<H>>) -> Result<(), DisplayError> {
         let (p0, p1) = UnixStream::pair()?;
         let p0 = prepare_uds_pass(&p0)?;
 
-        // println!("Registering listener via proxy")
         self.proxy.register_listener(p0).await?;
 
-        println!("Building D-Bus connection");
-        let connection_result = zbus::ConnectionBuilder::unix_stream(p1)
+        debug!("Serving the listener");
+        let builder = zbus::ConnectionBuilder::unix_stream(p1)
             .p2p()
-            .serve_at("/org/qemu/Display1/Listener", ConsoleListener::new(handler))?
-            .build()
-            .await;
-
-        match connection_result {
-            Ok(connection) => {
-                println!("Connection built successfully");
-                let mut listener_guard = self.listener.write().await;
-                *listener_guard = Some(connection);
-                println!("Registered listener");
-            }
-            Err(e) => {
-                println!("Failed to build connection: {}", e);
-            }
-        }
+            .serve_at("/org/qemu/Display1/Listener", ConsoleListener::new(Arc::clone(&handler)))?;
+        #[cfg(unix)]
+        let builder = builder.serve_at("/org/qemu/Display1/Listener", ConsoleListenerMap::new(handler))?;
+        let connection = builder.build().await?;
+
+        let mut listener_guard = self.listener.write().await;
+        *listener_guard = Some(connection);
+        info!("Registered listener");
         Ok(())
     }
 
+    /// Registers a listener and returns its events as a stream, waiting for room when the
+    /// consumer falls behind.
+    pub async fn events(&self) -> Result<ConsoleEvents, DisplayError> {
+        self.events_with(DEFAULT_EVENT_CAPACITY, OverflowPolicy::Block).await
+    }
+
+    /// Like `events`, buffering up to `capacity` events before `policy` applies.
+    pub async fn events_with(&self, capacity: usize, policy: OverflowPolicy) -> Result<ConsoleEvents, DisplayError> {
+        let (sender, events) = EventSender::channel(capacity, policy);
+        let disconnect = sender.disconnect_handle();
+        self.register_listener(sender).await?;
+        if let Some(connection) = self.listener.read().await.clone() {
+            watch_connection(connection, disconnect);
+        }
+        Ok(events)
+    }
+
+    /// Resolves once the listener connection is closed, e.g. because QEMU exited, or right
+    /// away if no listener is registered.
+    pub async fn listener_closed(&self) {
+        let Some(connection) = self.listener.read().await.clone() else {
+            return;
+        };
+
+        let mut messages = zbus::MessageStream::from(&connection);
+        while let Some(Ok(_)) = messages.next().await {}
+    }
+
     pub async fn unregister_listener(&self) {
         let mut listener_guard = self.listener.write().await;
         *listener_guard = None;
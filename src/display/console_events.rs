@@ -0,0 +1,349 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use async_trait::async_trait;
+use futures_util::{Stream, StreamExt};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use zbus::{Connection, MessageStream};
+use tracing::{info, warn};
+#[cfg(unix)]
+use crate::display::console_listenner::{ScanoutDMABUF, ScanoutMap, UpdateDMABUF, UpdateMap};
+use crate::display::console_listenner::{ConsoleListenerHandler, Cursor, MouseSet, Scanout, Update};
+
+/// Events buffered by `Console::events` before the overflow policy applies.
+pub const DEFAULT_EVENT_CAPACITY: usize = 64;
+
+/// What QEMU sent to the console listener, one variant per `ConsoleListenerHandler` method.
+#[derive(Debug)]
+pub enum ConsoleEvent {
+    Scanout(Scanout),
+    Update(Update),
+    #[cfg(unix)]
+    ScanoutDMABUF(ScanoutDMABUF),
+    #[cfg(unix)]
+    UpdateDMABUF(UpdateDMABUF),
+    #[cfg(unix)]
+    ScanoutMap(ScanoutMap),
+    #[cfg(unix)]
+    UpdateMap(UpdateMap),
+    MouseSet(MouseSet),
+    CursorDefine(Cursor),
+    /// The listener went away, the stream ends after it.
+    Disconnected,
+}
+
+impl ConsoleEvent {
+    /// Whether later events stay meaningful without this one, the next update redrawing what
+    /// it would have.
+    fn is_droppable(&self) -> bool {
+        match self {
+            ConsoleEvent::Update(_) | ConsoleEvent::MouseSet(_) => true,
+            #[cfg(unix)]
+            ConsoleEvent::UpdateDMABUF(_) | ConsoleEvent::UpdateMap(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// What happens to an event when the stream is full.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Wait for room, holding QEMU back until the consumer catches up.
+    #[default]
+    Block,
+    /// Drop the `Update*` and `MouseSet` events, QEMU is only held back by the scanouts and
+    /// cursor shapes the later events depend on, but the consumer misses frames.
+    DropNewest,
+}
+
+/// Events of a console listener, from `Console::events`.
+///
+/// Once the listener is disconnected, e.g. because QEMU exited, yields the buffered events,
+/// then `ConsoleEvent::Disconnected` and ends.
+#[derive(Debug)]
+pub struct ConsoleEvents {
+    receiver: Receiver<ConsoleEvent>,
+    /// Kept apart from the events so that a full buffer can't hold the disconnection back
+    disconnected: UnboundedReceiver<()>,
+    ended: bool,
+}
+
+impl Stream for ConsoleEvents {
+    type Item = ConsoleEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.ended {
+            return Poll::Ready(None);
+        }
+
+        let closed = match self.receiver.poll_recv(cx) {
+            Poll::Ready(Some(event)) => return Poll::Ready(Some(event)),
+            Poll::Ready(None) => true,
+            Poll::Pending => false,
+        };
+        match self.disconnected.poll_recv(cx) {
+            Poll::Ready(Some(())) => {
+                self.ended = true;
+                Poll::Ready(Some(ConsoleEvent::Disconnected))
+            }
+            Poll::Ready(None) if closed => {
+                self.ended = true;
+                Poll::Ready(None)
+            }
+            _ => Poll::Pending,
+        }
+    }
+}
+
+/// Ends the stream fed through the listener connection `connection` once it closes, with
+/// `disconnect` from `EventSender::disconnect_handle`.
+///
+/// QEMU going away doesn't drop the listener, which is served as long as the connection is
+/// kept, so the handler alone never sees it.
+pub(crate) fn watch_connection(connection: Connection, disconnect: UnboundedSender<()>) {
+    tokio::spawn(async move {
+        let closed = async {
+            let mut messages = MessageStream::from(&connection);
+            while let Some(Ok(_)) = messages.next().await {}
+        };
+
+        tokio::select! {
+            _ = closed => {
+                info!("Listener disconnected");
+                let _ = disconnect.send(());
+            }
+            // Nobody is listening anymore
+            _ = disconnect.closed() => (),
+        }
+    });
+}
+
+/// Listener handler feeding a `ConsoleEvents` stream.
+#[derive(Debug)]
+pub(crate) struct EventSender {
+    sender: Sender<ConsoleEvent>,
+    disconnect: UnboundedSender<()>,
+    policy: OverflowPolicy,
+    dropped: u64,
+}
+
+impl EventSender {
+    /// A handler and the stream it feeds, buffering up to `capacity` events.
+    pub(crate) fn channel(capacity: usize, policy: OverflowPolicy) -> (Self, ConsoleEvents) {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let (disconnect, disconnected) = mpsc::unbounded_channel();
+
+        (
+            Self {
+                sender,
+                disconnect,
+                policy,
+                dropped: 0,
+            },
+            ConsoleEvents { receiver, disconnected, ended: false },
+        )
+    }
+
+    /// Ends the stream from elsewhere, see `watch_connection`.
+    pub(crate) fn disconnect_handle(&self) -> UnboundedSender<()> {
+        self.disconnect.clone()
+    }
+
+    async fn send(&mut self, event: ConsoleEvent) {
+        match self.policy {
+            // A closed stream only means nobody is listening anymore
+            OverflowPolicy::Block => {
+                let _ = self.sender.send(event).await;
+            }
+            OverflowPolicy::DropNewest if !event.is_droppable() => {
+                let _ = self.sender.send(event).await;
+            }
+            OverflowPolicy::DropNewest => {
+                if let Err(TrySendError::Full(event)) = self.sender.try_send(event) {
+                    self.dropped += 1;
+                    warn!("Console event stream full, dropped {:?} ({} so far)", event, self.dropped);
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ConsoleListenerHandler for EventSender {
+    async fn scanout(&mut self, scanout: Scanout) {
+        self.send(ConsoleEvent::Scanout(scanout)).await;
+    }
+
+    async fn update(&mut self, update: Update) {
+        self.send(ConsoleEvent::Update(update)).await;
+    }
+
+    #[cfg(unix)]
+    async fn scanout_dmabuf(&mut self, scanout: ScanoutDMABUF) {
+        self.send(ConsoleEvent::ScanoutDMABUF(scanout)).await;
+    }
+
+    #[cfg(unix)]
+    async fn update_dmabuf(&mut self, update: UpdateDMABUF) {
+        self.send(ConsoleEvent::UpdateDMABUF(update)).await;
+    }
+
+    #[cfg(unix)]
+    async fn scanout_map(&mut self, scanout: ScanoutMap) {
+        self.send(ConsoleEvent::ScanoutMap(scanout)).await;
+    }
+
+    #[cfg(unix)]
+    async fn update_map(&mut self, update: UpdateMap) {
+        self.send(ConsoleEvent::UpdateMap(update)).await;
+    }
+
+    async fn mouse_set(&mut self, set: MouseSet) {
+        self.send(ConsoleEvent::MouseSet(set)).await;
+    }
+
+    async fn cursor_define(&mut self, cursor: Cursor) {
+        self.send(ConsoleEvent::CursorDefine(cursor)).await;
+    }
+
+    fn disconnected(&mut self) {
+        let _ = self.disconnect.send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+    use zbus::{ConnectionBuilder, Guid};
+
+    fn mouse_set(x: i32) -> MouseSet {
+        MouseSet { x, y: 0, on: 1 }
+    }
+
+    #[tokio::test]
+    async fn events_are_streamed_in_order() {
+        let (mut sender, mut events) = EventSender::channel(4, OverflowPolicy::Block);
+        sender.mouse_set(mouse_set(1)).await;
+        sender
+            .update_dmabuf(UpdateDMABUF { x: 0, y: 0, w: 2, h: 2 })
+            .await;
+        sender.disconnected();
+        drop(sender);
+
+        assert!(matches!(events.next().await, Some(ConsoleEvent::MouseSet(MouseSet { x: 1, .. }))));
+        assert!(matches!(events.next().await, Some(ConsoleEvent::UpdateDMABUF(_))));
+        assert!(matches!(events.next().await, Some(ConsoleEvent::Disconnected)));
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_the_oldest_events() {
+        let (mut sender, mut events) = EventSender::channel(2, OverflowPolicy::DropNewest);
+        for x in 0..5 {
+            sender.mouse_set(mouse_set(x)).await;
+        }
+        drop(sender);
+
+        let mut received = Vec::new();
+        while let Some(ConsoleEvent::MouseSet(set)) = events.next().await {
+            received.push(set.x);
+        }
+        assert_eq!(received, [0, 1]);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_the_scanouts() {
+        let (mut sender, mut events) = EventSender::channel(1, OverflowPolicy::DropNewest);
+        sender.mouse_set(mouse_set(0)).await;
+
+        let producer = tokio::spawn(async move {
+            sender.mouse_set(mouse_set(1)).await;
+            sender
+                .scanout(Scanout { width: 1, height: 1, stride: 4, pixman_format: 0, data: vec![0; 4] })
+                .await;
+        });
+        tokio::task::yield_now().await;
+        // The move is dropped, the scanout waits for room
+        assert!(!producer.is_finished());
+
+        assert!(matches!(events.next().await, Some(ConsoleEvent::MouseSet(MouseSet { x: 0, .. }))));
+        producer.await.unwrap();
+        assert!(matches!(events.next().await, Some(ConsoleEvent::Scanout(_))));
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (mut sender, mut events) = EventSender::channel(1, OverflowPolicy::Block);
+        sender.mouse_set(mouse_set(0)).await;
+
+        let producer = tokio::spawn(async move {
+            sender.mouse_set(mouse_set(1)).await;
+        });
+        tokio::task::yield_now().await;
+        assert!(!producer.is_finished());
+
+        assert!(matches!(events.next().await, Some(ConsoleEvent::MouseSet(MouseSet { x: 0, .. }))));
+        producer.await.unwrap();
+        assert!(matches!(events.next().await, Some(ConsoleEvent::MouseSet(MouseSet { x: 1, .. }))));
+    }
+
+    #[tokio::test]
+    async fn disconnected_is_delivered_when_full() {
+        let (mut sender, mut events) = EventSender::channel(1, OverflowPolicy::Block);
+        sender.mouse_set(mouse_set(0)).await;
+        sender.disconnected();
+
+        assert!(matches!(events.next().await, Some(ConsoleEvent::MouseSet(MouseSet { x: 0, .. }))));
+        assert!(matches!(events.next().await, Some(ConsoleEvent::Disconnected)));
+        // Even though the handler is still around
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn closing_the_peer_ends_the_stream() {
+        let (listener, qemu) = UnixStream::pair().unwrap();
+        let guid = Guid::generate();
+        let (listener, qemu) = tokio::try_join!(
+            ConnectionBuilder::unix_stream(listener).p2p().build(),
+            ConnectionBuilder::unix_stream(qemu).server(&guid).p2p().build(),
+        )
+        .unwrap();
+
+        let (mut sender, events) = EventSender::channel(1, OverflowPolicy::Block);
+        watch_connection(listener.clone(), sender.disconnect_handle());
+        sender.mouse_set(mouse_set(0)).await;
+
+        // QEMU exits
+        drop(qemu);
+
+        let received = tokio::time::timeout(Duration::from_secs(5), events.collect::<Vec<_>>()).await.unwrap();
+        assert!(matches!(
+            received[..],
+            [ConsoleEvent::MouseSet(MouseSet { x: 0, .. }), ConsoleEvent::Disconnected]
+        ));
+    }
+
+    /// Handlers only implement what they use.
+    struct FrameCounter(usize);
+
+    #[async_trait]
+    impl ConsoleListenerHandler for FrameCounter {
+        async fn update(&mut self, _update: Update) {
+            self.0 += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn partial_handlers() {
+        let mut counter = FrameCounter(0);
+        counter.mouse_set(mouse_set(0)).await;
+        counter
+            .update(Update { x: 0, y: 0, width: 1, height: 1, stride: 4, pixman_format: 0, data: vec![0; 4] })
+            .await;
+        counter.disconnected();
+
+        assert_eq!(counter.0, 1);
+    }
+}
@@ -1,178 +1,259 @@
+use std::sync::{Arc, Mutex};
 use async_trait::async_trait;
-use image::{ImageBuffer, RgbaImage};
-use libc::{MAP_SHARED, mmap, munmap, PROT_READ};
 use tokio::sync::mpsc::Sender;
-use crate::display::console_listenner::{ConsoleListenerHandler, Cursor, MouseSet, Scanout, ScanoutDMABUF, Update, UpdateDMABUF};
+use tracing::{debug, warn};
+use crate::display::console_listenner::{ConsoleListenerHandler, Cursor, MouseSet, Scanout, ScanoutDMABUF, ScanoutMap, Update, UpdateDMABUF, UpdateMap};
+use crate::display::cursor::CursorState;
+use crate::display::dmabuf::DmabufMapping;
+use crate::display::error::DisplayError;
+use crate::display::framebuffer::{Framebuffer, Rect};
+use crate::display::shared_map::SharedMap;
 use crate::display::utils::WindowCommand;
-use std::os::unix::io::AsRawFd;
+
+/// Guest surface shared through `ScanoutMap`, kept mapped until the next scanout.
+struct MappedScanout {
+    map: SharedMap,
+    offset: usize,
+    stride: u32,
+    format: u32,
+}
 
 pub struct DisplayHandlers {
     sender: Sender<WindowCommand>,
+    framebuffer: Arc<Mutex<Framebuffer>>,
+    cursor: Arc<Mutex<CursorState>>,
+    mapped: Option<MappedScanout>,
+    dmabuf: Option<DmabufMapping>,
 }
 
 impl DisplayHandlers {
     pub fn new(sender: Sender<WindowCommand>) -> Self {
-        Self { sender }
+        Self {
+            sender,
+            framebuffer: Arc::new(Mutex::new(Framebuffer::default())),
+            cursor: Arc::new(Mutex::new(CursorState::default())),
+            mapped: None,
+            dmabuf: None,
+        }
     }
-}
-
-#[async_trait]
-impl ConsoleListenerHandler for DisplayHandlers {
-    async fn scanout(&mut self, scanout: Scanout) {
-        println!("Scanout received: {:?}", scanout);
-
-        let img = scanout.to_image();
-
-        // Convert the image to a Vec<u32> for minifb
-        let buffer: Vec<u32> = img
-            .pixels()
-            .map(|p| {
-                let channels = p.0;
-                (u32::from(channels[2]) << 16) // Red
-                    | (u32::from(channels[1]) << 8)  // Green
-                    | u32::from(channels[0])         // Blue
-                    | (u32::from(channels[3]) << 24) // Alpha, if needed
-            })
-            .collect();
-
-        let vec_buffer = buffer.to_vec();
 
-        // Lock the window and update it with the buffer
-        self.sender.send(
-            WindowCommand::Update(
-                buffer,
-                scanout.width as usize,
-                scanout.height as usize
-            )
-        ).await.unwrap();
+    /// Shared handle on the framebuffer, for the window to read the damaged regions from.
+    pub fn framebuffer(&self) -> Arc<Mutex<Framebuffer>> {
+        Arc::clone(&self.framebuffer)
     }
 
-    async fn update(&mut self, update: Update) {
-        println!("Update received: {:?}", update);
+    /// Shared handle on the guest cursor, for the window to draw it.
+    pub fn cursor(&self) -> Arc<Mutex<CursorState>> {
+        Arc::clone(&self.cursor)
     }
 
-    #[cfg(unix)]
-    async fn scanout_dmabuf(&mut self, scanout: ScanoutDMABUF) {
-        println!("Scanout DMABUF received: {:?}", scanout);
-        let buffer = scanout.map_dmabuf_to_buffer();
-
-        self.sender.send(
-            WindowCommand::UpdateDMABUF(
-                buffer
-            )).await.unwrap();
-
+    /// Sends `command` to the window, which is only gone when the viewer is shutting down.
+    async fn send(&self, command: WindowCommand) {
+        if let Err(e) = self.sender.send(command).await {
+            debug!("Window command dropped: {}", DisplayError::from(e));
+        }
     }
 
-    #[cfg(unix)]
-    async fn update_dmabuf(&mut self, update: UpdateDMABUF) {
-        println!("Update DMABUF received: {:?}", update);
-    }
+    /// Applies `f` to the cursor and tells the window which areas it left and now covers.
+    async fn change_cursor(&self, f: impl FnOnce(&mut CursorState)) {
+        let rects = {
+            let framebuffer = self.framebuffer.lock().unwrap();
+            let mut cursor = self.cursor.lock().unwrap();
+            let old = cursor.rect(framebuffer.width, framebuffer.height);
+            f(&mut cursor);
+            let new = cursor.rect(framebuffer.width, framebuffer.height);
+            old.into_iter().chain(new).collect()
+        };
 
-    async fn mouse_set(&mut self, set: MouseSet) {
-        println!("MouseSet received: {:?}", set);
+        self.send(WindowCommand::Cursor(rects)).await;
     }
 
-    async fn cursor_define(&mut self, cursor: Cursor) {
-        println!("Cursor received: {:?}", cursor);
+    /// Copies a region of the DMABUF scanout into the framebuffer and returns the damage.
+    fn blit_dmabuf(&self, x: i32, y: i32, width: i32, height: i32) -> Option<Rect> {
+        let dmabuf = self.dmabuf.as_ref()?;
+
+        drop_bad_frame(dmabuf.copy_to(&mut self.framebuffer.lock().unwrap(), x, y, width, height))
     }
 
-    fn disconnected(&mut self) {
-        println!("Disconnected");
+    /// Copies a region of the mapped scanout into the framebuffer and returns the damage.
+    fn blit_mapped(&self, x: i32, y: i32, width: i32, height: i32) -> Option<Rect> {
+        let mapped = self.mapped.as_ref()?;
+        let surface = mapped.map.as_slice().get(mapped.offset..)?;
+
+        drop_bad_frame(self.framebuffer.lock().unwrap().blit_region(
+            x,
+            y,
+            width,
+            height,
+            mapped.stride,
+            mapped.format,
+            surface,
+        ))
     }
 }
 
-
-impl ScanoutDMABUF {
-    fn map_dmabuf_to_buffer(&self) -> Vec<u8>  {
-        let height = self.height;
-        let stride = self.stride;
-
-        let buffer = unsafe {
-            let size = (height  * stride) as usize;
-
-            let ptr = mmap(
-                std::ptr::null_mut(),
-                size,
-                PROT_READ,
-                MAP_SHARED,
-                self.fd.as_raw_fd(),
+#[async_trait]
+impl ConsoleListenerHandler for DisplayHandlers {
+    async fn scanout(&mut self, scanout: Scanout) {
+        let (resized, damage) = {
+            let mut framebuffer = self.framebuffer.lock().unwrap();
+            let resized = match framebuffer.resize(scanout.width, scanout.height) {
+                Ok(resized) => resized,
+                Err(e) => {
+                    warn!("Dropping scanout: {}", e);
+                    return;
+                }
+            };
+            let damage = framebuffer.blit(
                 0,
+                0,
+                scanout.width as i32,
+                scanout.height as i32,
+                scanout.stride,
+                scanout.pixman_format,
+                &scanout.data,
             );
+            (resized, drop_bad_frame(damage))
+        };
 
-            if ptr == libc::MAP_FAILED {
-                panic!("Failed to mmap DMABUF");
-            }
+        if resized {
+            self.send(WindowCommand::Resize(scanout.width as usize, scanout.height as usize)).await;
+        }
+        if let Some(rect) = damage {
+            self.send(WindowCommand::Damage(rect)).await;
+        }
+    }
 
-            let slice = std::slice::from_raw_parts(ptr as *mut u8, size);
-            let mut buffer: Vec<u8> = slice.to_vec();
+    async fn update(&mut self, update: Update) {
+        let damage = drop_bad_frame(self.framebuffer.lock().unwrap().blit(
+            update.x,
+            update.y,
+            update.width,
+            update.height,
+            update.stride,
+            update.pixman_format,
+            &update.data,
+        ));
+
+        if let Some(rect) = damage {
+            self.send(WindowCommand::Damage(rect)).await;
+        }
+    }
 
-            munmap(ptr, size);
-            buffer
+    #[cfg(unix)]
+    async fn scanout_dmabuf(&mut self, scanout: ScanoutDMABUF) {
+        // Drop the previous mapping before mapping the new buffer
+        self.dmabuf = None;
+        let (width, height) = (scanout.width, scanout.height);
+        match DmabufMapping::new(scanout) {
+            Ok(dmabuf) => self.dmabuf = Some(dmabuf),
+            Err(e) => {
+                warn!("Dropping DMABUF scanout: {}", e);
+                return;
+            }
+        }
+
+        let resized = match self.framebuffer.lock().unwrap().resize(width, height) {
+            Ok(resized) => resized,
+            Err(e) => {
+                warn!("Dropping DMABUF scanout: {}", e);
+                self.dmabuf = None;
+                return;
+            }
         };
+        if resized {
+            self.send(WindowCommand::Resize(width as usize, height as usize)).await;
+        }
 
-        buffer
+        if let Some(rect) = self.blit_dmabuf(0, 0, width as i32, height as i32) {
+            self.send(WindowCommand::Damage(rect)).await;
+        }
     }
 
-    fn to_pixel_buffer(&self, buffer: &[u8]) -> Vec<u32> {
-        let width = self.width;
-        let height = self.height;
-        let stride = self.stride;
-        let size = (height * stride) as usize;
+    #[cfg(unix)]
+    async fn update_dmabuf(&mut self, update: UpdateDMABUF) {
+        if let Some(rect) = self.blit_dmabuf(update.x, update.y, update.w, update.h) {
+            self.send(WindowCommand::Damage(rect)).await;
+        }
+    }
 
-        // Buffer de píxeles para minifb
-        let mut pixel_buffer: Vec<u32> = vec![0u32; (width * height) as usize];
-        //let mut pixel_buffer = ImageBuffer::new(width, height);
+    #[cfg(unix)]
+    async fn scanout_map(&mut self, scanout: ScanoutMap) {
+        // Drop the previous mapping before mapping the new surface
+        self.mapped = None;
+        let offset = scanout.offset as usize;
+        let map = (scanout.height as usize)
+            .checked_mul(scanout.stride as usize)
+            .and_then(|size| size.checked_add(offset))
+            .ok_or_else(|| {
+                DisplayError::Format(format!(
+                    "Mapped scanout of {} rows of {} bytes is too large",
+                    scanout.height, scanout.stride
+                ))
+            })
+            // The mapping outlives the descriptor, which `scanout` closes when dropped
+            .and_then(|size| SharedMap::new(scanout.fd, size));
+        match map {
+            Ok(map) => {
+                self.mapped = Some(MappedScanout {
+                    map,
+                    offset,
+                    stride: scanout.stride,
+                    format: scanout.format,
+                });
+            }
+            Err(e) => {
+                warn!("Dropping mapped scanout: {}", e);
+                return;
+            }
+        }
 
-        for y in 0..height {
-            for x in 0..width {
-                let offset = (y * stride + x * 4) as usize;
-                if offset + 3 < size {
-                    let pixel = (u32::from(buffer[offset + 3]) << 24) // Alpha
-                        | (u32::from(buffer[offset]) << 16)         // Red
-                        | (u32::from(buffer[offset + 1]) << 8)      // Green
-                        | u32::from(buffer[offset + 2]);            // Blue
-                    pixel_buffer[(y * width + x) as usize] = pixel;
-                }
+        let resized = match self.framebuffer.lock().unwrap().resize(scanout.width, scanout.height) {
+            Ok(resized) => resized,
+            Err(e) => {
+                warn!("Dropping mapped scanout: {}", e);
+                self.mapped = None;
+                return;
             }
+        };
+        if resized {
+            self.send(WindowCommand::Resize(scanout.width as usize, scanout.height as usize)).await;
         }
 
-        // let pixel_buffer: Vec<u32> = pixel_buffer
-        //     .pixels()
-        //     .map(|p| {
-        //         let channels = p.0;
-        //         (u32::from(channels[2]) << 16) // Red
-        //             | (u32::from(channels[1]) << 8)  // Green
-        //             | u32::from(channels[0])         // Blue
-        //             | (u32::from(channels[3]) << 24) // Alpha, if needed
-        //     })
-        //     .collect();
+        if let Some(rect) = self.blit_mapped(0, 0, scanout.width as i32, scanout.height as i32) {
+            self.send(WindowCommand::Damage(rect)).await;
+        }
+    }
 
-        pixel_buffer
+    #[cfg(unix)]
+    async fn update_map(&mut self, update: UpdateMap) {
+        if let Some(rect) = self.blit_mapped(update.x, update.y, update.w, update.h) {
+            self.send(WindowCommand::Damage(rect)).await;
+        }
     }
-}
 
+    async fn mouse_set(&mut self, set: MouseSet) {
+        self.change_cursor(|cursor| cursor.set_position(&set)).await;
+    }
 
-impl Scanout {
-    fn to_image(&self) -> RgbaImage {
-        let width = self.width as u32;
-        let height = self.height as u32;
-        let stride = self.stride as usize;
-
-        let mut img = ImageBuffer::new(width, height);
-
-        for y in 0..height {
-            for x in 0..width {
-                let index = (y as usize * stride) + (x as usize * 4); // Assuming 4 bytes per pixel (RGBA)
-                let pixel = [
-                    self.data[index],
-                    self.data[index + 1],
-                    self.data[index + 2],
-                    self.data[index + 3],
-                ];
-                img.put_pixel(x, y, image::Rgba(pixel));
+    async fn cursor_define(&mut self, cursor: Cursor) {
+        self.change_cursor(|state| {
+            if let Err(e) = state.define(&cursor) {
+                warn!("Dropping cursor: {}", e);
             }
-        }
+        })
+        .await;
+    }
 
-        img
+    fn disconnected(&mut self) {
+        debug!("Listener disconnected");
     }
-}
\ No newline at end of file
+}
+
+/// Logs why a frame could not be drawn and drops it, the next update redraws the area.
+fn drop_bad_frame(damage: Result<Option<Rect>, DisplayError>) -> Option<Rect> {
+    damage.unwrap_or_else(|e| {
+        warn!("Dropping frame: {}", e);
+        None
+    })
+}
@@ -1,8 +1,11 @@
 use derivative::Derivative;
 use std::ops::Drop;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 #[cfg(unix)]
 use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
 use zbus::dbus_interface;
+use tracing::instrument;
 #[cfg(unix)]
 use zbus::zvariant::Fd;
 
@@ -30,6 +33,7 @@ pub struct Update {
     pub data: Vec<u8>,
 }
 
+#[cfg(windows)]
 #[derive(Debug)]
 pub struct ScanoutMap {
     pub handle: u64,
@@ -40,6 +44,17 @@ pub struct ScanoutMap {
     pub format: u32,
 }
 
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct ScanoutMap {
+    pub fd: RawFd,
+    pub offset: u32,
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    pub format: u32,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct UpdateMap {
     pub x: i32,
@@ -89,6 +104,24 @@ impl IntoRawFd for ScanoutDMABUF {
     }
 }
 
+#[cfg(unix)]
+impl Drop for ScanoutMap {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl IntoRawFd for ScanoutMap {
+    fn into_raw_fd(mut self) -> RawFd {
+        std::mem::replace(&mut self.fd, -1)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct MouseSet {
     pub x: i32,
@@ -104,33 +137,44 @@ pub struct UpdateDMABUF {
     pub h: i32,
 }
 
+/// Receives what QEMU sends to the listener of a console.
+///
+/// Every method does nothing by default, so a handler only implements what it uses. See
+/// `Console::events` for a stream of the same events instead.
 #[async_trait::async_trait]
 pub trait ConsoleListenerHandler: 'static + Send + Sync {
 
-    async fn scanout(&mut self, scanout: Scanout);
+    async fn scanout(&mut self, _scanout: Scanout) {}
 
-    async fn update(&mut self, update: Update);
+    async fn update(&mut self, _update: Update) {}
 
     #[cfg(unix)]
-    async fn scanout_dmabuf(&mut self, scanout: ScanoutDMABUF);
+    async fn scanout_dmabuf(&mut self, _scanout: ScanoutDMABUF) {}
 
     #[cfg(unix)]
-    async fn update_dmabuf(&mut self, update: UpdateDMABUF);
+    async fn update_dmabuf(&mut self, _update: UpdateDMABUF) {}
 
-    async fn mouse_set(&mut self, set: MouseSet);
+    #[cfg(unix)]
+    async fn scanout_map(&mut self, _scanout: ScanoutMap) {}
+
+    #[cfg(unix)]
+    async fn update_map(&mut self, _update: UpdateMap) {}
 
-    async fn cursor_define(&mut self, cursor: Cursor);
+    async fn mouse_set(&mut self, _set: MouseSet) {}
 
-    fn disconnected(&mut self);
+    async fn cursor_define(&mut self, _cursor: Cursor) {}
+
+    fn disconnected(&mut self) {}
 }
 
 #[derive(Debug)]
 pub(crate) struct ConsoleListener<H: ConsoleListenerHandler> {
-    handler: H,
+    handler: Arc<Mutex<H>>,
 }
 
 #[dbus_interface(name = "org.qemu.Display1.Listener")]
 impl<H: ConsoleListenerHandler> ConsoleListener<H> {
+    #[instrument(level = "debug", skip(self, data))]
     async fn scanout(
         &mut self,
         width: u32,
@@ -140,6 +184,8 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
         data: Vec<u8>,
     ) {
         self.handler
+            .lock()
+            .await
             .scanout(Scanout {
                 width,
                 height,
@@ -150,6 +196,8 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
             .await;
     }
 
+    #[instrument(level = "trace", skip(self, data))]
+    #[allow(clippy::too_many_arguments)]
     async fn update(
         &mut self,
         x: i32,
@@ -161,6 +209,8 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
         data: Vec<u8>,
     ) {
         self.handler
+            .lock()
+            .await
             .update(Update {
                 x,
                 y,
@@ -175,30 +225,10 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
 
 
 
-    #[cfg(not(windows))]
-    async fn scanout_map(
-        &mut self,
-        _handle: u64,
-        _offset: u32,
-        _width: u32,
-        _height: u32,
-        _stride: u32,
-        _format: u32,
-    ) -> zbus::fdo::Result<()> {
-        Err(zbus::fdo::Error::NotSupported(
-            "Shared map is not support on !windows".into(),
-        ))
-    }
-
-    #[cfg(not(windows))]
-    async fn update_map(&mut self, _x: i32, _y: i32, _w: i32, _h: i32) -> zbus::fdo::Result<()> {
-        Err(zbus::fdo::Error::NotSupported(
-            "Shared map is not support on !windows".into(),
-        ))
-    }
-
     #[cfg(unix)]
+    #[instrument(level = "debug", skip(self))]
     #[dbus_interface(name = "ScanoutDMABUF")]
+    #[allow(clippy::too_many_arguments)]
     async fn scanout_dmabuf(
         &mut self,
         fd: Fd,
@@ -210,7 +240,12 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
         y0_top: bool,
     ) -> zbus::fdo::Result<()> {
         let fd = unsafe { libc::dup(fd.as_raw_fd()) };
+        if fd < 0 {
+            return Err(zbus::fdo::Error::IOError(std::io::Error::last_os_error().to_string()));
+        }
         self.handler
+            .lock()
+            .await
             .scanout_dmabuf(ScanoutDMABUF {
                 fd,
                 width,
@@ -225,18 +260,23 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
     }
 
     #[cfg(unix)]
+    #[instrument(level = "trace", skip(self))]
     #[dbus_interface(name = "UpdateDMABUF")]
     async fn update_dmabuf(&mut self, x: i32, y: i32, w: i32, h: i32) -> zbus::fdo::Result<()> {
         self.handler
+            .lock()
+            .await
             .update_dmabuf(UpdateDMABUF { x, y, w, h })
             .await;
         Ok(())
     }
 
+    #[instrument(level = "trace", skip(self))]
     async fn mouse_set(&mut self, x: i32, y: i32, on: i32) {
-        self.handler.mouse_set(MouseSet { x, y, on }).await;
+        self.handler.lock().await.mouse_set(MouseSet { x, y, on }).await;
     }
 
+    #[instrument(level = "debug", skip(self, data))]
     async fn cursor_define(
         &mut self,
         width: i32,
@@ -246,6 +286,8 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
         data: Vec<u8>,
     ) {
         self.handler
+            .lock()
+            .await
             .cursor_define(Cursor {
                 width,
                 height,
@@ -255,16 +297,85 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
             })
             .await;
     }
+
+    /// Extra listener interfaces QEMU may use, checked when the listener is registered.
+    #[dbus_interface(property)]
+    fn interfaces(&self) -> Vec<String> {
+        if cfg!(unix) {
+            vec!["org.qemu.Display1.Listener.Unix.Map".to_string()]
+        } else {
+            Vec::new()
+        }
+    }
 }
 
 impl<H: ConsoleListenerHandler> ConsoleListener<H> {
-    pub(crate) fn new(handler: H) -> Self {
+    pub(crate) fn new(handler: Arc<Mutex<H>>) -> Self {
         Self { handler }
     }
 }
 
 impl<H: ConsoleListenerHandler> Drop for ConsoleListener<H> {
     fn drop(&mut self) {
-        self.handler.disconnected();
+        if let Ok(mut handler) = self.handler.try_lock() {
+            handler.disconnected();
+        }
+    }
+}
+
+/// Shared-memory scanouts, served next to `ConsoleListener` and sharing its handler.
+#[cfg(unix)]
+#[derive(Debug)]
+pub(crate) struct ConsoleListenerMap<H: ConsoleListenerHandler> {
+    handler: Arc<Mutex<H>>,
+}
+
+#[cfg(unix)]
+#[dbus_interface(name = "org.qemu.Display1.Listener.Unix.Map")]
+impl<H: ConsoleListenerHandler> ConsoleListenerMap<H> {
+    #[instrument(level = "debug", skip(self))]
+    async fn scanout_map(
+        &mut self,
+        fd: Fd,
+        offset: u32,
+        width: u32,
+        height: u32,
+        stride: u32,
+        format: u32,
+    ) -> zbus::fdo::Result<()> {
+        let fd = unsafe { libc::dup(fd.as_raw_fd()) };
+        if fd < 0 {
+            return Err(zbus::fdo::Error::IOError(std::io::Error::last_os_error().to_string()));
+        }
+        self.handler
+            .lock()
+            .await
+            .scanout_map(ScanoutMap {
+                fd,
+                offset,
+                width,
+                height,
+                stride,
+                format,
+            })
+            .await;
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn update_map(&mut self, x: i32, y: i32, w: i32, h: i32) -> zbus::fdo::Result<()> {
+        self.handler
+            .lock()
+            .await
+            .update_map(UpdateMap { x, y, w, h })
+            .await;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl<H: ConsoleListenerHandler> ConsoleListenerMap<H> {
+    pub(crate) fn new(handler: Arc<Mutex<H>>) -> Self {
+        Self { handler }
     }
 }
\ No newline at end of file
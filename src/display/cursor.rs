@@ -0,0 +1,227 @@
+use std::str::FromStr;
+use crate::display::console_listenner::{Cursor, MouseSet};
+use crate::display::error::DisplayError;
+use crate::display::framebuffer::Rect;
+
+/// How the guest cursor is shown in the window.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum CursorMode {
+    /// Blend the guest cursor into the frame.
+    #[default]
+    Composite,
+    /// Turn the host pointer into the guest cursor bitmap, so that it moves without waiting for
+    /// the guest.
+    ///
+    /// Only the pixels backend on X11 can, elsewhere and while the pointer is grabbed the cursor
+    /// is composited.
+    Native,
+}
+
+/// Parses `composite` or `native`.
+impl FromStr for CursorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "composite" => Ok(CursorMode::Composite),
+            "native" => Ok(CursorMode::Native),
+            _ => Err(format!("Unknown cursor mode: {}", s)),
+        }
+    }
+}
+
+/// Guest cursor bitmap from `CursorDefine` and its position from `MouseSet`.
+#[derive(Debug, Default, Clone)]
+pub struct CursorState {
+    pub width: u32,
+    pub height: u32,
+    pub hot_x: i32,
+    pub hot_y: i32,
+    /// Pixels as `0xAARRGGBB`, not premultiplied.
+    pub data: Vec<u32>,
+    pub x: i32,
+    pub y: i32,
+    pub visible: bool,
+    /// Bumped on every `define`, to tell when the bitmap changed
+    pub serial: u64,
+}
+
+impl CursorState {
+    /// Fails on sizes that don't fit in memory, leaving the previous cursor in place.
+    pub fn define(&mut self, cursor: &Cursor) -> Result<(), DisplayError> {
+        let width = cursor.width.max(0) as u32;
+        let height = cursor.height.max(0) as u32;
+        let len = (width as usize)
+            .checked_mul(height as usize)
+            .ok_or_else(|| DisplayError::Format(format!("{}x{} cursor is too large", width, height)))?;
+
+        self.width = width;
+        self.height = height;
+        self.hot_x = cursor.hot_x;
+        self.hot_y = cursor.hot_y;
+        self.data = cursor
+            .data
+            .chunks_exact(4)
+            .map(|p| u32::from_le_bytes([p[0], p[1], p[2], p[3]]))
+            .take(len)
+            .collect();
+        self.serial += 1;
+        Ok(())
+    }
+
+    /// The bitmap with premultiplied alpha, as native cursors usually take it.
+    pub fn premultiplied(&self) -> impl Iterator<Item = u32> + '_ {
+        self.data.iter().map(|&pixel| {
+            let alpha = pixel >> 24;
+            let mul = |shift: u32| ((((pixel >> shift) & 0xff) * alpha) / 0xff) << shift;
+            (alpha << 24) | mul(16) | mul(8) | mul(0)
+        })
+    }
+
+    pub fn set_position(&mut self, set: &MouseSet) {
+        self.x = set.x;
+        self.y = set.y;
+        self.visible = set.on != 0;
+    }
+
+    /// Area of a `width`x`height` surface under the cursor, `None` when hidden or off-screen.
+    pub fn rect(&self, width: u32, height: u32) -> Option<Rect> {
+        if !self.visible || self.data.is_empty() {
+            return None;
+        }
+
+        Rect::clipped(
+            self.x - self.hot_x,
+            self.y - self.hot_y,
+            self.width as i32,
+            self.height as i32,
+            width,
+            height,
+        )
+    }
+
+    /// Returns `under` with the cursor blended over it at (`x`, `y`).
+    pub fn blend(&self, x: u32, y: u32, under: u32) -> u32 {
+        if !self.visible {
+            return under;
+        }
+
+        let cx = x as i32 - (self.x - self.hot_x);
+        let cy = y as i32 - (self.y - self.hot_y);
+        if cx < 0 || cy < 0 || cx >= self.width as i32 || cy >= self.height as i32 {
+            return under;
+        }
+
+        let pixel = match self.data.get((cy as u32 * self.width + cx as u32) as usize) {
+            Some(pixel) => *pixel,
+            None => return under,
+        };
+        let alpha = pixel >> 24;
+        match alpha {
+            0 => under,
+            0xff => pixel,
+            _ => {
+                let mix = |shift: u32| {
+                    let src = (pixel >> shift) & 0xff;
+                    let dst = (under >> shift) & 0xff;
+                    ((src * alpha + dst * (0xff - alpha)) / 0xff) << shift
+                };
+                0xff000000 | mix(16) | mix(8) | mix(0)
+            }
+        }
+    }
+
+    /// Blends the cursor over a `width`x`height` buffer of `0xAARRGGBB` pixels.
+    pub fn composite(&self, buffer: &mut [u32], width: u32, height: u32) {
+        let Some(rect) = self.rect(width, height) else {
+            return;
+        };
+
+        for y in rect.y..rect.y + rect.height {
+            for x in rect.x..rect.x + rect.width {
+                let index = (y * width + x) as usize;
+                buffer[index] = self.blend(x, y, buffer[index]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A visible 2x2 cursor with its hotspot on the bottom right pixel, at (`x`, `y`).
+    fn cursor(pixels: [u32; 4], x: i32, y: i32) -> CursorState {
+        let mut state = CursorState::default();
+        state.define(&Cursor {
+            width: 2,
+            height: 2,
+            hot_x: 1,
+            hot_y: 1,
+            data: pixels.iter().flat_map(|p| p.to_le_bytes()).collect(),
+        })
+        .unwrap();
+        state.set_position(&MouseSet { x, y, on: 1 });
+        state
+    }
+
+    #[test]
+    fn rect_is_offset_by_the_hotspot_and_clipped() {
+        let state = cursor([0xff000000; 4], 5, 5);
+        assert_eq!(state.rect(10, 10), Some(Rect { x: 4, y: 4, width: 2, height: 2 }));
+
+        let state = cursor([0xff000000; 4], 0, 0);
+        assert_eq!(state.rect(10, 10), Some(Rect { x: 0, y: 0, width: 1, height: 1 }));
+
+        let state = cursor([0xff000000; 4], 12, 5);
+        assert_eq!(state.rect(10, 10), None);
+    }
+
+    #[test]
+    fn premultiplied_scales_by_alpha() {
+        let state = cursor([0xffff8000, 0x80ffffff, 0x00ffffff, 0x40800000], 1, 1);
+        assert_eq!(
+            state.premultiplied().collect::<Vec<_>>(),
+            [0xffff8000, 0x80808080, 0x00000000, 0x40200000]
+        );
+    }
+
+    #[test]
+    fn define_bumps_the_serial() {
+        let mut state = cursor([0; 4], 0, 0);
+        let serial = state.serial;
+        state.set_position(&MouseSet { x: 1, y: 1, on: 1 });
+        assert_eq!(state.serial, serial);
+        state.define(&Cursor { width: 1, height: 1, hot_x: 0, hot_y: 0, data: vec![0; 4] }).unwrap();
+        assert_ne!(state.serial, serial);
+    }
+
+    #[test]
+    fn cursor_modes_are_parsed() {
+        assert_eq!("composite".parse(), Ok(CursorMode::Composite));
+        assert_eq!("native".parse(), Ok(CursorMode::Native));
+        assert!("hardware".parse::<CursorMode>().is_err());
+    }
+
+    #[test]
+    fn hidden_cursor_has_no_rect() {
+        let mut state = cursor([0xff000000; 4], 5, 5);
+        state.set_position(&MouseSet { x: 5, y: 5, on: 0 });
+
+        assert_eq!(state.rect(10, 10), None);
+        assert_eq!(state.blend(4, 4, 0xff123456), 0xff123456);
+    }
+
+    #[test]
+    fn blend_honors_alpha() {
+        let state = cursor([0xffff0000, 0x00ff0000, 0x80ffffff, 0xff00ff00], 1, 1);
+        let under = 0xff000000;
+
+        assert_eq!(state.blend(0, 0, under), 0xffff0000);
+        assert_eq!(state.blend(1, 0, under), under);
+        assert_eq!(state.blend(0, 1, under), 0xff808080);
+        assert_eq!(state.blend(1, 1, under), 0xff00ff00);
+        // Outside of the cursor
+        assert_eq!(state.blend(2, 2, under), under);
+    }
+}
@@ -0,0 +1,247 @@
+use std::io;
+use std::os::unix::io::{IntoRawFd, RawFd};
+use crate::display::console_listenner::ScanoutDMABUF;
+use crate::display::error::DisplayError;
+use crate::display::drm_format::{fourcc_to_string, DrmFormat, DRM_FORMAT_MOD_INVALID, DRM_FORMAT_MOD_LINEAR};
+use crate::display::framebuffer::{Framebuffer, Rect};
+use crate::display::shared_map::SharedMap;
+
+// From linux/dma-buf.h
+const DMA_BUF_SYNC_READ: u64 = 1 << 0;
+const DMA_BUF_SYNC_START: u64 = 0 << 2;
+const DMA_BUF_SYNC_END: u64 = 1 << 2;
+// _IOW('b', 0, struct dma_buf_sync)
+const DMA_BUF_IOCTL_SYNC: libc::c_ulong = 0x40086200;
+
+#[repr(C)]
+struct DmaBufSync {
+    flags: u64,
+}
+
+/// A `ScanoutDMABUF` mapped once for reading, until the next scanout replaces it.
+#[derive(Debug)]
+pub struct DmabufMapping {
+    map: SharedMap,
+    fd: RawFd,
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    pub format: DrmFormat,
+    pub y0_top: bool,
+}
+
+impl DmabufMapping {
+    /// Takes ownership of the scanout fd, which stays open for the sync ioctls.
+    ///
+    /// Fails on formats we can't decode and on tiled or compressed modifiers, whose memory
+    /// layout can't be read linearly through mmap.
+    pub fn new(scanout: ScanoutDMABUF) -> Result<Self, DisplayError> {
+        let Some(format) = DrmFormat::from_fourcc(scanout.fourcc) else {
+            return Err(DisplayError::Format(format!(
+                "DMABUF format {}",
+                fourcc_to_string(scanout.fourcc)
+            )));
+        };
+        // The implicit layout is driver specific and may well be tiled, so only trust linear
+        if scanout.modifier == DRM_FORMAT_MOD_INVALID {
+            return Err(DisplayError::Format("DMABUF without an explicit modifier".to_string()));
+        }
+        if scanout.modifier != DRM_FORMAT_MOD_LINEAR {
+            return Err(DisplayError::Format(format!(
+                "Non-linear DMABUF modifier {:#x} can't be mapped",
+                scanout.modifier
+            )));
+        }
+
+        let width = scanout.width;
+        let height = scanout.height;
+        let stride = scanout.stride;
+        let y0_top = scanout.y0_top;
+        let Some(size) = (height as usize).checked_mul(stride as usize) else {
+            return Err(DisplayError::Format(format!(
+                "DMABUF of {} rows of {} bytes is too large",
+                height, stride
+            )));
+        };
+        let fd = scanout.into_raw_fd();
+
+        let map = match SharedMap::new(fd, size) {
+            Ok(map) => map,
+            Err(e) => {
+                unsafe {
+                    libc::close(fd);
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            map,
+            fd,
+            width,
+            height,
+            stride,
+            format,
+            y0_top,
+        })
+    }
+
+    fn sync(&self, flags: u64) -> Result<(), DisplayError> {
+        let sync = DmaBufSync { flags };
+        let ret = unsafe { libc::ioctl(self.fd, DMA_BUF_IOCTL_SYNC as _, &sync) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            // Not a real DMABUF (e.g. a memfd), nothing to synchronize
+            if err.raw_os_error() == Some(libc::ENOTTY) {
+                return Ok(());
+            }
+            return Err(DisplayError::Mmap(err));
+        }
+        Ok(())
+    }
+
+    /// Runs `f` on the mapped buffer, bracketed by DMA_BUF_IOCTL_SYNC start and end.
+    pub fn read<R>(&self, f: impl FnOnce(&[u8]) -> R) -> Result<R, DisplayError> {
+        self.sync(DMA_BUF_SYNC_START | DMA_BUF_SYNC_READ)?;
+        let ret = f(self.map.as_slice());
+        self.sync(DMA_BUF_SYNC_END | DMA_BUF_SYNC_READ)?;
+        Ok(ret)
+    }
+
+    /// Copies the damaged region into the framebuffer and returns it clipped.
+    ///
+    /// Buffers with `y0_top` unset are stored bottom-up and get flipped.
+    pub fn copy_to(
+        &self,
+        framebuffer: &mut Framebuffer,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+    ) -> Result<Option<Rect>, DisplayError> {
+        let Some(rect) = framebuffer.clip(x, y, width, height) else {
+            return Ok(None);
+        };
+        let bpp = self.format.bytes_per_pixel();
+
+        self.read(|buffer| {
+            for dst_y in rect.y..(rect.y + rect.height).min(self.height) {
+                let src_y = if self.y0_top { dst_y } else { self.height - 1 - dst_y };
+                let row = (src_y * self.stride) as usize;
+                let dst_row = (dst_y * framebuffer.width) as usize;
+
+                for dst_x in rect.x..rect.x + rect.width {
+                    let offset = row + dst_x as usize * bpp;
+                    let Some(bytes) = buffer.get(offset..offset + bpp) else {
+                        break;
+                    };
+                    framebuffer.data[dst_row + dst_x as usize] = self.format.to_argb(bytes);
+                }
+            }
+        })?;
+
+        Ok(Some(rect))
+    }
+}
+
+impl Drop for DmabufMapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
+    const XRGB8888: u32 = 0x34325258;
+
+    fn memfd_scanout(width: u32, height: u32, data: &[u8]) -> ScanoutDMABUF {
+        let fd = unsafe { libc::memfd_create(c"dmabuf".as_ptr(), 0) };
+        assert!(fd >= 0);
+
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(data).unwrap();
+
+        ScanoutDMABUF {
+            fd: file.into_raw_fd(),
+            width,
+            height,
+            stride: width * 4,
+            fourcc: XRGB8888,
+            modifier: 0,
+            y0_top: true,
+        }
+    }
+
+    #[test]
+    fn copies_only_the_damaged_rect() {
+        let data: Vec<u8> = (0..4 * 4).flat_map(|i| [i as u8, 0, 0, 0]).collect();
+        let mapping = DmabufMapping::new(memfd_scanout(4, 4, &data)).unwrap();
+
+        let mut framebuffer = Framebuffer::default();
+        framebuffer.resize(4, 4).unwrap();
+        let rect = mapping.copy_to(&mut framebuffer, 1, 2, 2, 5).unwrap();
+
+        assert_eq!(rect, Some(Rect { x: 1, y: 2, width: 2, height: 2 }));
+        for y in 0..4 {
+            for x in 0..4 {
+                let expected = if (1..3).contains(&x) && y >= 2 {
+                    0xff000000 | (y * 4 + x)
+                } else {
+                    0
+                };
+                assert_eq!(framebuffer.data[(y * 4 + x) as usize], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn flips_bottom_up_buffers() {
+        let data: Vec<u8> = (0..2 * 2).flat_map(|i| [i as u8, 0, 0, 0]).collect();
+        let mut scanout = memfd_scanout(2, 2, &data);
+        scanout.y0_top = false;
+        let mapping = DmabufMapping::new(scanout).unwrap();
+
+        let mut framebuffer = Framebuffer::default();
+        framebuffer.resize(2, 2).unwrap();
+        mapping.copy_to(&mut framebuffer, 0, 0, 2, 2).unwrap();
+
+        assert_eq!(framebuffer.data, vec![0xff000002, 0xff000003, 0xff000000, 0xff000001]);
+    }
+
+    #[test]
+    fn rejects_tiled_modifiers() {
+        let mut scanout = memfd_scanout(2, 2, &[0; 16]);
+        // I915_FORMAT_MOD_X_TILED
+        scanout.modifier = 0x0100000000000001;
+
+        let err = DmabufMapping::new(scanout).unwrap_err();
+        assert!(matches!(err, DisplayError::Format(_)));
+    }
+
+    #[test]
+    fn rejects_implicit_modifiers() {
+        let mut scanout = memfd_scanout(2, 2, &[0; 16]);
+        scanout.modifier = DRM_FORMAT_MOD_INVALID;
+
+        let err = DmabufMapping::new(scanout).unwrap_err();
+        assert!(matches!(err, DisplayError::Format(_)));
+    }
+
+    #[test]
+    fn unmappable_fd_is_an_error() {
+        let mut scanout = memfd_scanout(4, 4, &[]);
+        unsafe {
+            libc::close(scanout.fd);
+        }
+        scanout.fd = -1;
+
+        assert!(matches!(DmabufMapping::new(scanout), Err(DisplayError::Mmap(_))));
+    }
+}
@@ -0,0 +1,148 @@
+/// DRM_FORMAT_MOD_LINEAR
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+/// DRM_FORMAT_MOD_INVALID, sent when the exporter has no explicit modifier and the layout is
+/// up to the driver.
+pub const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
+const fn fourcc_code(a: u8, b: u8, c: u8, d: u8) -> u32 {
+    (a as u32) | (b as u32) << 8 | (c as u32) << 16 | (d as u32) << 24
+}
+
+/// DRM formats that can be decoded from a linear DMABUF, named as in drm_fourcc.h.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DrmFormat {
+    Xrgb8888,
+    Argb8888,
+    Xbgr8888,
+    Abgr8888,
+    Rgb565,
+    Xrgb2101010,
+    Argb2101010,
+    Xbgr2101010,
+    Abgr2101010,
+}
+
+impl DrmFormat {
+    pub fn from_fourcc(fourcc: u32) -> Option<Self> {
+        const XRGB8888: u32 = fourcc_code(b'X', b'R', b'2', b'4');
+        const ARGB8888: u32 = fourcc_code(b'A', b'R', b'2', b'4');
+        const XBGR8888: u32 = fourcc_code(b'X', b'B', b'2', b'4');
+        const ABGR8888: u32 = fourcc_code(b'A', b'B', b'2', b'4');
+        const RGB565: u32 = fourcc_code(b'R', b'G', b'1', b'6');
+        const XRGB2101010: u32 = fourcc_code(b'X', b'R', b'3', b'0');
+        const ARGB2101010: u32 = fourcc_code(b'A', b'R', b'3', b'0');
+        const XBGR2101010: u32 = fourcc_code(b'X', b'B', b'3', b'0');
+        const ABGR2101010: u32 = fourcc_code(b'A', b'B', b'3', b'0');
+
+        match fourcc {
+            XRGB8888 => Some(DrmFormat::Xrgb8888),
+            ARGB8888 => Some(DrmFormat::Argb8888),
+            XBGR8888 => Some(DrmFormat::Xbgr8888),
+            ABGR8888 => Some(DrmFormat::Abgr8888),
+            RGB565 => Some(DrmFormat::Rgb565),
+            XRGB2101010 => Some(DrmFormat::Xrgb2101010),
+            ARGB2101010 => Some(DrmFormat::Argb2101010),
+            XBGR2101010 => Some(DrmFormat::Xbgr2101010),
+            ABGR2101010 => Some(DrmFormat::Abgr2101010),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            DrmFormat::Rgb565 => 2,
+            _ => 4,
+        }
+    }
+
+    /// Decodes one little-endian pixel into `0xAARRGGBB`.
+    pub fn to_argb(&self, bytes: &[u8]) -> u32 {
+        if *self == DrmFormat::Rgb565 {
+            let word = u16::from_le_bytes([bytes[0], bytes[1]]) as u32;
+            let r = (word >> 11) & 0x1f;
+            let g = (word >> 5) & 0x3f;
+            let b = word & 0x1f;
+            return 0xff000000
+                | ((r << 3) | (r >> 2)) << 16
+                | ((g << 2) | (g >> 4)) << 8
+                | ((b << 3) | (b >> 2));
+        }
+
+        let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        match self {
+            DrmFormat::Xrgb8888 => word | 0xff000000,
+            DrmFormat::Argb8888 => word,
+            DrmFormat::Xbgr8888 => 0xff000000 | swap_red_blue(word),
+            DrmFormat::Abgr8888 => (word & 0xff000000) | swap_red_blue(word),
+            DrmFormat::Xrgb2101010 | DrmFormat::Argb2101010 => {
+                let alpha = if *self == DrmFormat::Argb2101010 { (word >> 30) * 0x55 } else { 0xff };
+                pack_10bit(alpha, word >> 20, word >> 10, word)
+            }
+            DrmFormat::Xbgr2101010 | DrmFormat::Abgr2101010 => {
+                let alpha = if *self == DrmFormat::Abgr2101010 { (word >> 30) * 0x55 } else { 0xff };
+                pack_10bit(alpha, word, word >> 10, word >> 20)
+            }
+            DrmFormat::Rgb565 => unreachable!(),
+        }
+    }
+}
+
+/// Prints a fourcc as its four characters, e.g. `XR24`.
+pub fn fourcc_to_string(fourcc: u32) -> String {
+    fourcc
+        .to_le_bytes()
+        .iter()
+        .map(|&c| if c.is_ascii_graphic() { c as char } else { '?' })
+        .collect()
+}
+
+fn swap_red_blue(word: u32) -> u32 {
+    (word & 0x0000ff00) | (word & 0xff) << 16 | (word >> 16) & 0xff
+}
+
+/// Keeps the 8 most significant bits of each 10-bit channel.
+fn pack_10bit(alpha: u32, r: u32, g: u32, b: u32) -> u32 {
+    alpha << 24 | ((r & 0x3ff) >> 2) << 16 | ((g & 0x3ff) >> 2) << 8 | (b & 0x3ff) >> 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(fourcc: &[u8; 4], word: u32) -> u32 {
+        let format = DrmFormat::from_fourcc(fourcc_code(fourcc[0], fourcc[1], fourcc[2], fourcc[3])).unwrap();
+        format.to_argb(&word.to_le_bytes()[..format.bytes_per_pixel()])
+    }
+
+    #[test]
+    fn rgb565_expands_to_8_bits() {
+        assert_eq!(decode(b"RG16", 0xf800), 0xffff0000);
+        assert_eq!(decode(b"RG16", 0x07e0), 0xff00ff00);
+        assert_eq!(decode(b"RG16", 0x001f), 0xff0000ff);
+        assert_eq!(decode(b"RG16", 0x8410), 0xff848284);
+    }
+
+    #[test]
+    fn bgr_swaps_red_and_blue() {
+        assert_eq!(decode(b"XB24", 0x00112233), 0xff332211);
+        assert_eq!(decode(b"AB24", 0x80112233), 0x80332211);
+        assert_eq!(decode(b"XR24", 0x00112233), 0xff112233);
+        assert_eq!(decode(b"AR24", 0x80112233), 0x80112233);
+    }
+
+    #[test]
+    fn ten_bit_keeps_the_high_bits() {
+        // r = 0x3ff, g = 0x200, b = 0x004
+        let rgb = 0x3ff << 20 | 0x200 << 10 | 0x004;
+        assert_eq!(decode(b"XR30", rgb), 0xffff8001);
+        assert_eq!(decode(b"AR30", 0b10 << 30 | rgb), 0xaaff8001);
+        assert_eq!(decode(b"XB30", rgb), 0xff0180ff);
+        assert_eq!(decode(b"AB30", 0b01 << 30 | rgb), 0x550180ff);
+    }
+
+    #[test]
+    fn unknown_fourcc() {
+        assert_eq!(DrmFormat::from_fourcc(fourcc_code(b'N', b'V', b'1', b'2')), None);
+        assert_eq!(fourcc_to_string(fourcc_code(b'N', b'V', b'1', b'2')), "NV12");
+    }
+}
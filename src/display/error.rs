@@ -0,0 +1,56 @@
+use std::io;
+
+/// Errors of the D-Bus display client.
+#[derive(Debug, thiserror::Error)]
+pub enum DisplayError {
+    /// A D-Bus call, proxy or connection failed
+    #[error("D-Bus error: {0}")]
+    DBus(#[from] zbus::Error),
+    /// Sockets, ptys and files
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// Mapping a shared surface or a DMABUF failed
+    #[error("Failed to map memory: {0}")]
+    Mmap(#[source] io::Error),
+    /// Pixel data QEMU sent that cannot be displayed
+    #[error("Unsupported format: {0}")]
+    Format(String),
+    /// The window or the task on the other end went away
+    #[error("Channel closed")]
+    ChannelClosed,
+    /// QEMU answered something unexpected, over D-Bus or QMP
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+}
+
+impl From<zbus::fdo::Error> for DisplayError {
+    fn from(e: zbus::fdo::Error) -> Self {
+        DisplayError::DBus(e.into())
+    }
+}
+
+impl From<zbus::zvariant::Error> for DisplayError {
+    fn from(e: zbus::zvariant::Error) -> Self {
+        DisplayError::DBus(e.into())
+    }
+}
+
+impl From<zbus::names::Error> for DisplayError {
+    fn from(e: zbus::names::Error) -> Self {
+        DisplayError::DBus(e.into())
+    }
+}
+
+impl From<serde_json::Error> for DisplayError {
+    fn from(e: serde_json::Error) -> Self {
+        DisplayError::Protocol(e.to_string())
+    }
+}
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for DisplayError {
+    fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        DisplayError::ChannelClosed
+    }
+}
+
+pub type Result<T> = std::result::Result<T, DisplayError>;
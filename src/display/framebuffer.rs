@@ -0,0 +1,134 @@
+use crate::display::error::DisplayError;
+use crate::display::pixman_format::PixmanFormat;
+
+/// Damaged region of the framebuffer, in guest pixels.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// Clips a region to a `bounds_width`x`bounds_height` surface, `None` if they don't overlap.
+    pub fn clipped(x: i32, y: i32, width: i32, height: i32, bounds_width: u32, bounds_height: u32) -> Option<Rect> {
+        let x0 = x.max(0);
+        let y0 = y.max(0);
+        let x1 = x.saturating_add(width).min(bounds_width as i32);
+        let y1 = y.saturating_add(height).min(bounds_height as i32);
+        if x0 >= x1 || y0 >= y1 {
+            return None;
+        }
+
+        Some(Rect {
+            x: x0 as u32,
+            y: y0 as u32,
+            width: (x1 - x0) as u32,
+            height: (y1 - y0) as u32,
+        })
+    }
+}
+
+/// Persistent copy of the guest display, kept in sync by `Scanout` and patched by `Update`.
+///
+/// Every source format is converted to `0xAARRGGBB`, `width` pixels per row, which is what
+/// minifb expects.
+#[derive(Debug, Default)]
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u32>,
+}
+
+impl Framebuffer {
+    /// Reallocates the buffer if the size changed. Returns `true` when it did.
+    ///
+    /// Fails on sizes that don't fit in memory, leaving the framebuffer untouched.
+    pub fn resize(&mut self, width: u32, height: u32) -> Result<bool, DisplayError> {
+        if self.width == width && self.height == height {
+            return Ok(false);
+        }
+
+        let len = (width as usize)
+            .checked_mul(height as usize)
+            .ok_or_else(|| DisplayError::Format(format!("{}x{} surface is too large", width, height)))?;
+        self.width = width;
+        self.height = height;
+        self.data = vec![0; len];
+        Ok(true)
+    }
+
+    /// Copies a `width`x`height` block of `data`, laid out with `stride` bytes per row in
+    /// `pixman_format`, at (`x`, `y`).
+    ///
+    /// Returns the damaged rectangle clipped to the framebuffer, or `None` if nothing was drawn.
+    /// Fails on pixel formats we can't decode, leaving the framebuffer untouched.
+    #[allow(clippy::too_many_arguments)]
+    pub fn blit(
+        &mut self,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+        stride: u32,
+        pixman_format: u32,
+        data: &[u8],
+    ) -> Result<Option<Rect>, DisplayError> {
+        let Some(format) = PixmanFormat::from_code(pixman_format) else {
+            return Err(DisplayError::Format(format!("pixman format {:#x}", pixman_format)));
+        };
+        let bpp = format.bytes_per_pixel();
+
+        let Some(rect) = self.clip(x, y, width, height) else {
+            return Ok(None);
+        };
+
+        let stride = stride as usize;
+        for dst_y in rect.y..rect.y + rect.height {
+            let row = (dst_y as i32 - y) as usize * stride;
+            let dst_row = (dst_y * self.width) as usize;
+
+            for dst_x in rect.x..rect.x + rect.width {
+                let offset = row + (dst_x as i32 - x) as usize * bpp;
+                let Some(bytes) = data.get(offset..offset + bpp) else {
+                    break;
+                };
+                self.data[dst_row + dst_x as usize] = format.to_argb(bytes);
+            }
+        }
+
+        Ok(Some(rect))
+    }
+
+    /// Clips a region to the framebuffer, `None` if they don't overlap.
+    pub fn clip(&self, x: i32, y: i32, width: i32, height: i32) -> Option<Rect> {
+        Rect::clipped(x, y, width, height, self.width, self.height)
+    }
+
+    /// Like `blit`, but `surface` holds the whole guest surface rather than just the region,
+    /// as with the shared-memory and DMABUF scanouts.
+    #[allow(clippy::too_many_arguments)]
+    pub fn blit_region(
+        &mut self,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+        stride: u32,
+        pixman_format: u32,
+        surface: &[u8],
+    ) -> Result<Option<Rect>, DisplayError> {
+        let x0 = x.max(0);
+        let y0 = y.max(0);
+        let width = x.saturating_add(width) - x0;
+        let height = y.saturating_add(height) - y0;
+
+        let bpp = PixmanFormat::from_code(pixman_format).map_or(4, |format| format.bytes_per_pixel());
+        let start = y0 as usize * stride as usize + x0 as usize * bpp;
+        let Some(region) = surface.get(start..) else {
+            return Ok(None);
+        };
+        self.blit(x0, y0, width, height, stride, pixman_format, region)
+    }
+}
@@ -0,0 +1,466 @@
+use std::str::FromStr;
+use winit::event::{ModifiersState, VirtualKeyCode};
+
+/// Key combination handled by the viewer instead of being sent to the guest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Hotkey {
+    pub modifiers: ModifiersState,
+    pub key: VirtualKeyCode,
+}
+
+impl Hotkey {
+    pub fn matches(&self, modifiers: ModifiersState, key: Option<VirtualKeyCode>) -> bool {
+        key == Some(self.key) && modifiers.contains(self.modifiers)
+    }
+}
+
+impl Default for Hotkey {
+    /// Ctrl+Alt+G
+    fn default() -> Self {
+        Self {
+            modifiers: ModifiersState::CTRL | ModifiersState::ALT,
+            key: VirtualKeyCode::G,
+        }
+    }
+}
+
+/// Parses modifiers and a key joined with `+`, e.g. `ctrl+alt+g` or `Shift+F12`.
+///
+/// The key is a letter, a digit, `F1` to `F24` or one of a few named keys like `escape`.
+impl FromStr for Hotkey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts: Vec<&str> = s.split('+').map(str::trim).collect();
+        let key = parts.pop().filter(|key| !key.is_empty()).ok_or_else(|| format!("No key in {}", s))?;
+
+        let mut modifiers = ModifiersState::empty();
+        for modifier in parts {
+            modifiers |= match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => ModifiersState::CTRL,
+                "alt" => ModifiersState::ALT,
+                "shift" => ModifiersState::SHIFT,
+                "super" | "logo" | "meta" => ModifiersState::LOGO,
+                _ => return Err(format!("Unknown modifier: {}", modifier)),
+            };
+        }
+        let key = virtual_keycode_from_name(key).ok_or_else(|| format!("Unknown key: {}", key))?;
+
+        Ok(Self { modifiers, key })
+    }
+}
+
+fn virtual_keycode_from_name(name: &str) -> Option<VirtualKeyCode> {
+    use VirtualKeyCode::*;
+
+    const LETTERS: [VirtualKeyCode; 26] = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    const DIGITS: [VirtualKeyCode; 10] = [Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9];
+    const FUNCTION_KEYS: [VirtualKeyCode; 24] = [
+        F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+        F13, F14, F15, F16, F17, F18, F19, F20, F21, F22, F23, F24,
+    ];
+
+    let name = name.to_ascii_lowercase();
+    let mut chars = name.chars();
+    match (chars.next()?, chars.as_str()) {
+        (c @ 'a'..='z', "") => return Some(LETTERS[(c as u8 - b'a') as usize]),
+        (c @ '0'..='9', "") => return Some(DIGITS[(c as u8 - b'0') as usize]),
+        ('f', number) => {
+            if let Ok(n @ 1..=24) = number.parse::<usize>() {
+                return Some(FUNCTION_KEYS[n - 1]);
+            }
+        }
+        _ => (),
+    }
+
+    let key = match name.as_str() {
+        "escape" | "esc" => Escape,
+        "space" => Space,
+        "tab" => Tab,
+        "return" | "enter" => Return,
+        "backspace" => Back,
+        "insert" => Insert,
+        "delete" => Delete,
+        "home" => Home,
+        "end" => End,
+        "pageup" => PageUp,
+        "pagedown" => PageDown,
+        "up" => Up,
+        "down" => Down,
+        "left" => Left,
+        "right" => Right,
+        "pause" => Pause,
+        "scrolllock" => Scroll,
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// Returns the keycode `org.qemu.Display1.Keyboard.Press` expects for a key event, preferring
+/// the layout-independent scancode.
+///
+/// QEMU takes "qnum" codes: PC set 1 scancodes, with the `0xe0` prefix of extended keys folded
+/// into the high bit (`0xe0 0x1d`, right control, is `0x9d`).
+pub fn qnum_from_winit(scancode: u32, virtual_keycode: Option<VirtualKeyCode>) -> Option<u32> {
+    qnum_from_scancode(scancode).or_else(|| virtual_keycode.and_then(qnum_from_virtual_keycode))
+}
+
+/// On Linux winit scancodes are evdev keycodes.
+#[cfg(target_os = "linux")]
+fn qnum_from_scancode(scancode: u32) -> Option<u32> {
+    qnum_from_evdev(scancode)
+}
+
+/// On Windows winit scancodes are set 1 scancodes, with `0xe000` set on extended keys.
+#[cfg(target_os = "windows")]
+fn qnum_from_scancode(scancode: u32) -> Option<u32> {
+    let code = scancode & 0x7f;
+    match (scancode & 0xff00, code) {
+        (_, 0) => None,
+        (0, _) => Some(code),
+        (0xe000, _) => Some(0x80 | code),
+        _ => None,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn qnum_from_scancode(_scancode: u32) -> Option<u32> {
+    None
+}
+
+/// Maps a Linux evdev keycode (`KEY_*` in input-event-codes.h) to a qnum.
+pub fn qnum_from_evdev(keycode: u32) -> Option<u32> {
+    let qnum = match keycode {
+        // KEY_ESC to KEY_KPDOT share their values with set 1
+        1..=83 => keycode,
+        85 => 0x76,  // KEY_ZENKAKUHANKAKU
+        86 => 0x56,  // KEY_102ND
+        87 => 0x57,  // KEY_F11
+        88 => 0x58,  // KEY_F12
+        89 => 0x73,  // KEY_RO
+        90 => 0x78,  // KEY_KATAKANA
+        91 => 0x77,  // KEY_HIRAGANA
+        92 => 0x79,  // KEY_HENKAN
+        93 => 0x70,  // KEY_KATAKANAHIRAGANA
+        94 => 0x7b,  // KEY_MUHENKAN
+        95 => 0x5c,  // KEY_KPJPCOMMA
+        96 => 0x9c,  // KEY_KPENTER
+        97 => 0x9d,  // KEY_RIGHTCTRL
+        98 => 0xb5,  // KEY_KPSLASH
+        99 => 0x54,  // KEY_SYSRQ
+        100 => 0xb8, // KEY_RIGHTALT
+        102 => 0xc7, // KEY_HOME
+        103 => 0xc8, // KEY_UP
+        104 => 0xc9, // KEY_PAGEUP
+        105 => 0xcb, // KEY_LEFT
+        106 => 0xcd, // KEY_RIGHT
+        107 => 0xcf, // KEY_END
+        108 => 0xd0, // KEY_DOWN
+        109 => 0xd1, // KEY_PAGEDOWN
+        110 => 0xd2, // KEY_INSERT
+        111 => 0xd3, // KEY_DELETE
+        113 => 0xa0, // KEY_MUTE
+        114 => 0xae, // KEY_VOLUMEDOWN
+        115 => 0xb0, // KEY_VOLUMEUP
+        116 => 0xde, // KEY_POWER
+        117 => 0x59, // KEY_KPEQUAL
+        119 => 0xc6, // KEY_PAUSE
+        121 => 0x7e, // KEY_KPCOMMA
+        122 => 0xf2, // KEY_HANGEUL
+        123 => 0xf1, // KEY_HANJA
+        124 => 0x7d, // KEY_YEN
+        125 => 0xdb, // KEY_LEFTMETA
+        126 => 0xdc, // KEY_RIGHTMETA
+        127 => 0xdd, // KEY_COMPOSE
+        142 => 0xdf, // KEY_SLEEP
+        143 => 0xe3, // KEY_WAKEUP
+        163 => 0x99, // KEY_NEXTSONG
+        164 => 0xa2, // KEY_PLAYPAUSE
+        165 => 0x90, // KEY_PREVIOUSSONG
+        166 => 0xa4, // KEY_STOPCD
+        210 => 0xb7, // KEY_PRINT
+        _ => return None,
+    };
+    Some(qnum)
+}
+
+/// Maps a winit virtual keycode to a qnum, for platforms where the scancode can't be used.
+///
+/// The virtual keycode follows the host layout, so this assumes a US layout for the
+/// alphanumeric block.
+pub fn qnum_from_virtual_keycode(key: VirtualKeyCode) -> Option<u32> {
+    use VirtualKeyCode::*;
+
+    let qnum = match key {
+        Escape => 0x01,
+        Key1 => 0x02,
+        Key2 => 0x03,
+        Key3 => 0x04,
+        Key4 => 0x05,
+        Key5 => 0x06,
+        Key6 => 0x07,
+        Key7 => 0x08,
+        Key8 => 0x09,
+        Key9 => 0x0a,
+        Key0 => 0x0b,
+        Minus => 0x0c,
+        Equals => 0x0d,
+        Back => 0x0e,
+        Tab => 0x0f,
+        Q => 0x10,
+        W => 0x11,
+        E => 0x12,
+        R => 0x13,
+        T => 0x14,
+        Y => 0x15,
+        U => 0x16,
+        I => 0x17,
+        O => 0x18,
+        P => 0x19,
+        LBracket => 0x1a,
+        RBracket => 0x1b,
+        Return => 0x1c,
+        LControl => 0x1d,
+        A => 0x1e,
+        S => 0x1f,
+        D => 0x20,
+        F => 0x21,
+        G => 0x22,
+        H => 0x23,
+        J => 0x24,
+        K => 0x25,
+        L => 0x26,
+        Semicolon => 0x27,
+        Apostrophe => 0x28,
+        Grave => 0x29,
+        LShift => 0x2a,
+        Backslash => 0x2b,
+        Z => 0x2c,
+        X => 0x2d,
+        C => 0x2e,
+        V => 0x2f,
+        B => 0x30,
+        N => 0x31,
+        M => 0x32,
+        Comma => 0x33,
+        Period => 0x34,
+        Slash => 0x35,
+        RShift => 0x36,
+        NumpadMultiply => 0x37,
+        LAlt => 0x38,
+        Space => 0x39,
+        Capital => 0x3a,
+        F1 => 0x3b,
+        F2 => 0x3c,
+        F3 => 0x3d,
+        F4 => 0x3e,
+        F5 => 0x3f,
+        F6 => 0x40,
+        F7 => 0x41,
+        F8 => 0x42,
+        F9 => 0x43,
+        F10 => 0x44,
+        Numlock => 0x45,
+        Scroll => 0x46,
+        Numpad7 => 0x47,
+        Numpad8 => 0x48,
+        Numpad9 => 0x49,
+        NumpadSubtract => 0x4a,
+        Numpad4 => 0x4b,
+        Numpad5 => 0x4c,
+        Numpad6 => 0x4d,
+        NumpadAdd => 0x4e,
+        Numpad1 => 0x4f,
+        Numpad2 => 0x50,
+        Numpad3 => 0x51,
+        Numpad0 => 0x52,
+        NumpadDecimal => 0x53,
+        Sysrq => 0x54,
+        OEM102 => 0x56,
+        F11 => 0x57,
+        F12 => 0x58,
+        NumpadEquals => 0x59,
+        Kana => 0x70,
+        AbntC1 => 0x73,
+        Convert => 0x79,
+        NoConvert => 0x7b,
+        Yen => 0x7d,
+        AbntC2 | NumpadComma => 0x7e,
+        PrevTrack => 0x90,
+        NextTrack => 0x99,
+        NumpadEnter => 0x9c,
+        RControl => 0x9d,
+        Mute => 0xa0,
+        PlayPause => 0xa2,
+        MediaStop => 0xa4,
+        VolumeDown => 0xae,
+        VolumeUp => 0xb0,
+        NumpadDivide => 0xb5,
+        Snapshot => 0xb7,
+        RAlt => 0xb8,
+        Pause => 0xc6,
+        Home => 0xc7,
+        Up => 0xc8,
+        PageUp => 0xc9,
+        Left => 0xcb,
+        Right => 0xcd,
+        End => 0xcf,
+        Down => 0xd0,
+        PageDown => 0xd1,
+        Insert => 0xd2,
+        Delete => 0xd3,
+        LWin => 0xdb,
+        RWin => 0xdc,
+        Apps => 0xdd,
+        Power => 0xde,
+        Sleep => 0xdf,
+        Wake => 0xe3,
+        Kanji => 0xf1,
+        _ => return None,
+    };
+    Some(qnum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Keys with the same physical key on both sides, as (evdev, virtual keycode)
+    const PAIRS: &[(u32, VirtualKeyCode)] = &[
+        (1, VirtualKeyCode::Escape),
+        (2, VirtualKeyCode::Key1),
+        (11, VirtualKeyCode::Key0),
+        (14, VirtualKeyCode::Back),
+        (16, VirtualKeyCode::Q),
+        (28, VirtualKeyCode::Return),
+        (29, VirtualKeyCode::LControl),
+        (30, VirtualKeyCode::A),
+        (42, VirtualKeyCode::LShift),
+        (50, VirtualKeyCode::M),
+        (54, VirtualKeyCode::RShift),
+        (55, VirtualKeyCode::NumpadMultiply),
+        (56, VirtualKeyCode::LAlt),
+        (57, VirtualKeyCode::Space),
+        (58, VirtualKeyCode::Capital),
+        (59, VirtualKeyCode::F1),
+        (69, VirtualKeyCode::Numlock),
+        (71, VirtualKeyCode::Numpad7),
+        (74, VirtualKeyCode::NumpadSubtract),
+        (78, VirtualKeyCode::NumpadAdd),
+        (82, VirtualKeyCode::Numpad0),
+        (83, VirtualKeyCode::NumpadDecimal),
+        (86, VirtualKeyCode::OEM102),
+        (87, VirtualKeyCode::F11),
+        (88, VirtualKeyCode::F12),
+        (89, VirtualKeyCode::AbntC1),
+        (92, VirtualKeyCode::Convert),
+        (94, VirtualKeyCode::NoConvert),
+        (96, VirtualKeyCode::NumpadEnter),
+        (97, VirtualKeyCode::RControl),
+        (98, VirtualKeyCode::NumpadDivide),
+        (100, VirtualKeyCode::RAlt),
+        (102, VirtualKeyCode::Home),
+        (103, VirtualKeyCode::Up),
+        (105, VirtualKeyCode::Left),
+        (106, VirtualKeyCode::Right),
+        (108, VirtualKeyCode::Down),
+        (111, VirtualKeyCode::Delete),
+        (117, VirtualKeyCode::NumpadEquals),
+        (119, VirtualKeyCode::Pause),
+        (121, VirtualKeyCode::NumpadComma),
+        (123, VirtualKeyCode::Kanji),
+        (124, VirtualKeyCode::Yen),
+        (125, VirtualKeyCode::LWin),
+        (126, VirtualKeyCode::RWin),
+        (127, VirtualKeyCode::Apps),
+        (210, VirtualKeyCode::Snapshot),
+    ];
+
+    #[test]
+    fn evdev_and_virtual_keycodes_agree() {
+        for (evdev, key) in PAIRS {
+            assert_eq!(qnum_from_evdev(*evdev), qnum_from_virtual_keycode(*key), "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn modifiers() {
+        assert_eq!(qnum_from_virtual_keycode(VirtualKeyCode::LShift), Some(0x2a));
+        assert_eq!(qnum_from_virtual_keycode(VirtualKeyCode::RShift), Some(0x36));
+        assert_eq!(qnum_from_virtual_keycode(VirtualKeyCode::LControl), Some(0x1d));
+        assert_eq!(qnum_from_virtual_keycode(VirtualKeyCode::RControl), Some(0x9d));
+        assert_eq!(qnum_from_virtual_keycode(VirtualKeyCode::LAlt), Some(0x38));
+        assert_eq!(qnum_from_virtual_keycode(VirtualKeyCode::RAlt), Some(0xb8));
+        assert_eq!(qnum_from_virtual_keycode(VirtualKeyCode::LWin), Some(0xdb));
+        assert_eq!(qnum_from_virtual_keycode(VirtualKeyCode::RWin), Some(0xdc));
+    }
+
+    #[test]
+    fn keypad_is_distinct_from_the_main_block() {
+        let pairs = [
+            (VirtualKeyCode::NumpadEnter, VirtualKeyCode::Return),
+            (VirtualKeyCode::NumpadDivide, VirtualKeyCode::Slash),
+            (VirtualKeyCode::Numpad7, VirtualKeyCode::Home),
+            (VirtualKeyCode::Numpad8, VirtualKeyCode::Up),
+            (VirtualKeyCode::Numpad0, VirtualKeyCode::Insert),
+            (VirtualKeyCode::NumpadDecimal, VirtualKeyCode::Delete),
+        ];
+
+        for (keypad, main) in pairs {
+            let keypad = qnum_from_virtual_keycode(keypad).unwrap();
+            let main = qnum_from_virtual_keycode(main).unwrap();
+            // Same set 1 code, only the extended bit differs for the navigation keys
+            assert_ne!(keypad, main);
+        }
+    }
+
+    #[test]
+    fn international_keys() {
+        assert_eq!(qnum_from_evdev(86), Some(0x56)); // KEY_102ND
+        assert_eq!(qnum_from_evdev(89), Some(0x73)); // KEY_RO
+        assert_eq!(qnum_from_evdev(124), Some(0x7d)); // KEY_YEN
+        assert_eq!(qnum_from_evdev(122), Some(0xf2)); // KEY_HANGEUL
+        assert_eq!(qnum_from_virtual_keycode(VirtualKeyCode::Kana), Some(0x70));
+        assert_eq!(qnum_from_virtual_keycode(VirtualKeyCode::AbntC2), Some(0x7e));
+    }
+
+    #[test]
+    fn qnums_are_unique() {
+        let mut seen: HashMap<u32, u32> = HashMap::new();
+        for evdev in 0..256 {
+            if let Some(qnum) = qnum_from_evdev(evdev) {
+                assert!(qnum > 0 && qnum < 0x100);
+                if let Some(other) = seen.insert(qnum, evdev) {
+                    panic!("evdev {} and {} both map to {:#x}", other, evdev, qnum);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hotkeys_are_parsed() {
+        assert_eq!("ctrl+alt+g".parse(), Ok(Hotkey::default()));
+        assert_eq!(
+            "Shift + F12".parse(),
+            Ok(Hotkey { modifiers: ModifiersState::SHIFT, key: VirtualKeyCode::F12 })
+        );
+        assert_eq!("escape".parse(), Ok(Hotkey { modifiers: ModifiersState::empty(), key: VirtualKeyCode::Escape }));
+        assert_eq!("super+1".parse::<Hotkey>().map(|hotkey| hotkey.key), Ok(VirtualKeyCode::Key1));
+
+        assert!("ctrl+".parse::<Hotkey>().is_err());
+        assert!("hyper+g".parse::<Hotkey>().is_err());
+        assert!("ctrl+f25".parse::<Hotkey>().is_err());
+        assert!("ctrl+gg".parse::<Hotkey>().is_err());
+    }
+
+    #[test]
+    fn unknown_keys() {
+        assert_eq!(qnum_from_evdev(0), None);
+        assert_eq!(qnum_from_evdev(84), None);
+        assert_eq!(qnum_from_virtual_keycode(VirtualKeyCode::F24), None);
+        assert_eq!(qnum_from_winit(0, None), None);
+    }
+}
@@ -1,123 +1,413 @@
-use std::error::Error;
-use std::sync::Arc;
-use minifb::{Key, Window, WindowOptions};
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::{Receiver, Sender};
-use crate::display::console::Console;
-use crate::display::console_handler::DisplayHandlers;
-use crate::display::utils::WindowCommand;
-
-async fn build_minifb_window(
-    sender: Sender<WindowCommand>,
-    mut receiver: Receiver<WindowCommand>,
-    console_handler: Arc<Result<Console, Box<dyn Error + Send + Sync>>>
-) {
-    let (thread_sender, mut thread_receiver): (Sender<WindowCommand>, Receiver<WindowCommand>) = mpsc::channel(100);
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use minifb::{Key, KeyRepeat, MouseMode, ScaleMode, Window, WindowOptions};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, error, info, warn};
+use crate::display::cursor::{CursorMode, CursorState};
+use crate::display::framebuffer::{Framebuffer, Rect};
+use crate::display::mouse::MouseButton;
+use crate::display::supervisor::ConnectionState;
+use crate::display::utils::{ViewerOptions, WindowCommand};
+use crate::display::viewer::{dispatch_commands, DisplaySink, InputSource, Viewer};
+
+pub async fn build_minifb_window(viewer: Viewer, options: ViewerOptions) {
+    let Viewer { framebuffer, cursor, mut commands, input } = viewer;
+    if options.cursor_mode == CursorMode::Native {
+        warn!("minifb can't show custom cursors, compositing the cursor instead");
+    }
 
     let window_thread = std::thread::spawn(move || {
-        let mut window = match Window::new(
-            "Qemu Display Buffer",
+        let window = match Window::new(
+            WINDOW_TITLE,
             1280,
             800,
-            {
-                let mut options = WindowOptions::default();
-                options.resize = true;
-                options
+            WindowOptions {
+                resize: true,
+                scale_mode: ScaleMode::AspectRatioStretch,
+                ..WindowOptions::default()
             }
         ) {
             Ok(win) => win,
             Err(e) => {
-                eprintln!("Failed to create window: {}", e);
-                std::process::exit(1);
+                error!("Failed to create window: {}", e);
+                return;
             }
         };
 
-        // let target_fps = 60.0;
-        // let frame_duration = std::time::Duration::from_secs_f64(1.0 / target_fps);
-
-        while window.is_open() && !window.is_key_down(Key::Escape) {
-            // let frame_start = std::time::Instant::now();
-
-            // let mouse_pos = window.get_mouse_pos(MouseMode::Clamp);
-            //
-            // if let Some((x, y)) = mouse_pos {
-            //     let _ = thread_sender.blocking_send(WindowCommand::MouseMove(x, y)).unwrap();
-            // }
-
-            // Recibir comando del canal en el hilo principal
-            if let Ok(command) = receiver.try_recv() {
-                match command {
-                    WindowCommand::Update(buffer, width, height) => {
-                        window.update_with_buffer(&buffer, width, height).unwrap();
-                    },
-                    _ => {}
-                }
-            }
+        let mut minifb_window = MinifbWindow {
+            window,
+            framebuffer,
+            cursor,
+            buffer: Vec::new(),
+            damaged: false,
+            last_mouse_pos: None,
+            buttons_down: [false; 3],
+            pressed_keys: HashSet::new(),
+            absolute: true,
+        };
 
-            // Wait for the next frame
-            // let elapsed = frame_start.elapsed();
-            // if elapsed < frame_duration {
-            //     std::thread::sleep(frame_duration - elapsed);
-            // }
+        while minifb_window.window.is_open() {
+            minifb_window.poll_input(&input);
+            dispatch_commands(&mut commands, &mut minifb_window);
+            minifb_window.present();
         }
     });
 
-    // Connect to the console and register the DBus listener
-    let sender_clone = sender.clone();
-    let cloned_console_handler_2 = Arc::clone(&console_handler);
-    tokio::spawn(async move {
-        match cloned_console_handler_2.as_ref() {
-            Ok(console) => {
-                println!("Connected to console");
-                let handlers = DisplayHandlers::new(sender_clone);
-                let _ = console.register_listener(handlers).await.unwrap();
+    if window_thread.join().is_err() {
+        error!("Window thread panicked");
+    }
+}
+
+const WINDOW_TITLE: &str = "Qemu Display Buffer";
+
+/// minifb window, which can only be polled and only presents whole buffers.
+struct MinifbWindow {
+    window: Window,
+    framebuffer: Arc<Mutex<Framebuffer>>,
+    cursor: Arc<Mutex<CursorState>>,
+    /// Frame presented to minifb, the framebuffer with the cursor blended in
+    buffer: Vec<u32>,
+    /// Something changed since the last frame was presented
+    damaged: bool,
+    /// Last mouse state sent to the guest, in guest coordinates in absolute mode and window
+    /// coordinates in relative mode
+    last_mouse_pos: Option<(f32, f32)>,
+    buttons_down: [bool; 3],
+    /// Keys held down in the guest, released if the window loses focus
+    pressed_keys: HashSet<u32>,
+    /// In relative mode the motion over the window is sent as deltas, minifb can't grab the
+    /// pointer
+    absolute: bool,
+}
+
+impl MinifbWindow {
+    /// Presents the framebuffer if anything changed, coalescing the damage into one update.
+    fn present(&mut self) {
+        if !self.damaged {
+            self.window.update();
+            return;
+        }
+        self.damaged = false;
+
+        let framebuffer = self.framebuffer.lock().unwrap();
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&framebuffer.data);
+        self.cursor.lock().unwrap().composite(&mut self.buffer, framebuffer.width, framebuffer.height);
+        if let Err(e) = self.window.update_with_buffer(
+            &self.buffer,
+            framebuffer.width as usize,
+            framebuffer.height as usize
+        ) {
+            warn!("Dropping frame: {}", e);
+        }
+    }
+}
+
+impl DisplaySink for MinifbWindow {
+    fn on_resize(&mut self, _width: u32, _height: u32) {
+        self.damaged = true;
+    }
+
+    fn on_damage(&mut self, _rect: Rect) {
+        self.damaged = true;
+    }
+
+    fn on_cursor(&mut self, _rects: Vec<Rect>) {
+        self.damaged = true;
+    }
+
+    fn on_absolute(&mut self, absolute: bool) {
+        debug!("Guest mouse is {}", if absolute { "absolute" } else { "relative" });
+        self.absolute = absolute;
+        // The positions of one mode mean nothing in the other
+        self.last_mouse_pos = None;
+        self.window.set_cursor_visibility(absolute);
+    }
+
+    fn on_state(&mut self, state: ConnectionState) {
+        info!("Connection state: {:?}", state);
+        match state {
+            ConnectionState::Connected => self.window.set_title(WINDOW_TITLE),
+            ConnectionState::Disconnected => {
+                self.window.set_title(&format!("{} (disconnected)", WINDOW_TITLE));
             }
-            Err(e) => {
-                panic!("Error: {}", e);
+            ConnectionState::Reconnecting { attempt, delay } => {
+                self.window.set_title(&format!(
+                    "{} (reconnecting in {:.1}s, attempt {})",
+                    WINDOW_TITLE,
+                    delay.as_secs_f32(),
+                    attempt
+                ));
             }
         }
-    });
+    }
+}
 
-    // Procesar comandos recibidos asincrónicamente
-    tokio::spawn({
-        async move {
-            while let Some(command) = thread_receiver.recv().await {
-                match command {
-                    WindowCommand::MouseMove(x, y) => {
-                        let cloned_console_handler_3 = Arc::clone(&console_handler);
-                        tokio::spawn(async move {
-                            println!("Received mouse event: ({}, {})", x, y);
-                            match cloned_console_handler_3.as_ref(){
-                                Ok(guard) => {
-                                    guard.mouse.set_abs_position(x as u32, y as u32).await.unwrap();
-                                }
-                                Err(e) => {
-                                    println!("No console found {:?}", e);
-                                }
-                            }
-                            // update_mouse_position(cloned_console_handler_3, x, y).await
-                        });
-                    }
-                    _ => {}
-                }
+impl InputSource for MinifbWindow {
+    fn poll_input(&mut self, input: &UnboundedSender<WindowCommand>) {
+        if self.window.is_active() {
+            self.poll_keys(input);
+        } else {
+            for qnum in self.pressed_keys.drain() {
+                let _ = input.send(WindowCommand::KeyRelease(qnum));
+            }
+        }
+
+        if self.absolute {
+            self.poll_absolute_motion(input);
+        } else {
+            self.poll_relative_motion(input);
+        }
+
+        let buttons = [
+            (minifb::MouseButton::Left, MouseButton::Left),
+            (minifb::MouseButton::Middle, MouseButton::Middle),
+            (minifb::MouseButton::Right, MouseButton::Right),
+        ];
+        for (i, (minifb_button, button)) in buttons.into_iter().enumerate() {
+            let down = self.window.get_mouse_down(minifb_button);
+            if down != self.buttons_down[i] {
+                self.buttons_down[i] = down;
+                let command = if down {
+                    WindowCommand::MousePress(button)
+                } else {
+                    WindowCommand::MouseRelease(button)
+                };
+                let _ = input.send(command);
             }
         }
-    });
 
-    // Esperar a que el hilo de la ventana termine
-    window_thread.join().unwrap();
+        // Horizontal scrolling has no button in the guest
+        if let Some((_, y)) = self.window.get_scroll_wheel().filter(|&(_, y)| y != 0.0) {
+            let button = if y > 0.0 { MouseButton::WheelUp } else { MouseButton::WheelDown };
+            let _ = input.send(WindowCommand::MousePress(button));
+            let _ = input.send(WindowCommand::MouseRelease(button));
+        }
+    }
+}
+
+impl MinifbWindow {
+    fn poll_keys(&mut self, input: &UnboundedSender<WindowCommand>) {
+        for key in self.window.get_keys_pressed(KeyRepeat::No) {
+            if let Some(qnum) = qnum_from_key(key) {
+                self.pressed_keys.insert(qnum);
+                let _ = input.send(WindowCommand::KeyPress(qnum));
+            }
+        }
+        for key in self.window.get_keys_released() {
+            if let Some(qnum) = qnum_from_key(key) {
+                self.pressed_keys.remove(&qnum);
+                let _ = input.send(WindowCommand::KeyRelease(qnum));
+            }
+        }
+    }
+
+    fn poll_absolute_motion(&mut self, input: &UnboundedSender<WindowCommand>) {
+        let window_size = self.window.get_size();
+        let guest_size = {
+            let framebuffer = self.framebuffer.lock().unwrap();
+            (framebuffer.width, framebuffer.height)
+        };
+        let position = self
+            .window
+            .get_unscaled_mouse_pos(MouseMode::Discard)
+            .and_then(|position| window_to_guest(position, window_size, guest_size));
+        if let Some(position) = position {
+            if self.last_mouse_pos != Some(position) {
+                self.last_mouse_pos = Some(position);
+                let _ = input.send(WindowCommand::MouseMove(position.0, position.1));
+            }
+        }
+    }
+
+    /// Sends the motion since the last poll, as long as the pointer stays over the window.
+    fn poll_relative_motion(&mut self, input: &UnboundedSender<WindowCommand>) {
+        let position = self.window.get_unscaled_mouse_pos(MouseMode::Discard);
+        if let (Some(last), Some(position)) = (self.last_mouse_pos, position) {
+            if last != position {
+                let (dx, dy) = (position.0 - last.0, position.1 - last.1);
+                let _ = input.send(WindowCommand::MouseRelMove(dx as f64, dy as f64));
+            }
+        }
+        self.last_mouse_pos = position;
+    }
 }
 
-// async fn update_mouse_position(console:Arc<Mutex<Result<Console, Box<dyn Error + Send + Sync>>>>, x: f32, y: f32) {
-//     println!("Entering update_mouse_position: ({}, {})", x, y);
-//     match console.lock().await.as_mut() {
-//         Ok(guard) => {
-//             println!("Guard: {:?}", guard);
-//
-//             guard.mouse.set_abs_position(x as u32, y as u32).await.unwrap();
-//         }
-//         Err(e) => {
-//             println!("No console found {:?}", e);
-//         }
-//     }
-// }
\ No newline at end of file
+/// Scales a window position back to the guest, the buffer being stretched over the window with
+/// its aspect ratio kept. `None` over the borders around it.
+fn window_to_guest(position: (f32, f32), window: (usize, usize), guest: (u32, u32)) -> Option<(f32, f32)> {
+    if window.0 == 0 || window.1 == 0 || guest.0 == 0 || guest.1 == 0 {
+        return None;
+    }
+
+    let scale = (window.0 as f32 / guest.0 as f32).min(window.1 as f32 / guest.1 as f32);
+    let left = (window.0 as f32 - guest.0 as f32 * scale) / 2.0;
+    let top = (window.1 as f32 - guest.1 as f32 * scale) / 2.0;
+    let x = (position.0 - left) / scale;
+    let y = (position.1 - top) / scale;
+    if x < 0.0 || y < 0.0 || x >= guest.0 as f32 || y >= guest.1 as f32 {
+        return None;
+    }
+
+    Some((x, y))
+}
+
+/// Maps a minifb key to a qnum, see `keymap::qnum_from_winit`.
+///
+/// minifb only reports keys by their meaning on a US layout, and not all of them.
+fn qnum_from_key(key: Key) -> Option<u32> {
+    let qnum = match key {
+        Key::Escape => 0x01,
+        Key::Key1 => 0x02,
+        Key::Key2 => 0x03,
+        Key::Key3 => 0x04,
+        Key::Key4 => 0x05,
+        Key::Key5 => 0x06,
+        Key::Key6 => 0x07,
+        Key::Key7 => 0x08,
+        Key::Key8 => 0x09,
+        Key::Key9 => 0x0a,
+        Key::Key0 => 0x0b,
+        Key::Minus => 0x0c,
+        Key::Equal => 0x0d,
+        Key::Backspace => 0x0e,
+        Key::Tab => 0x0f,
+        Key::Q => 0x10,
+        Key::W => 0x11,
+        Key::E => 0x12,
+        Key::R => 0x13,
+        Key::T => 0x14,
+        Key::Y => 0x15,
+        Key::U => 0x16,
+        Key::I => 0x17,
+        Key::O => 0x18,
+        Key::P => 0x19,
+        Key::LeftBracket => 0x1a,
+        Key::RightBracket => 0x1b,
+        Key::Enter => 0x1c,
+        Key::LeftCtrl => 0x1d,
+        Key::A => 0x1e,
+        Key::S => 0x1f,
+        Key::D => 0x20,
+        Key::F => 0x21,
+        Key::G => 0x22,
+        Key::H => 0x23,
+        Key::J => 0x24,
+        Key::K => 0x25,
+        Key::L => 0x26,
+        Key::Semicolon => 0x27,
+        Key::Apostrophe => 0x28,
+        Key::Backquote => 0x29,
+        Key::LeftShift => 0x2a,
+        Key::Backslash => 0x2b,
+        Key::Z => 0x2c,
+        Key::X => 0x2d,
+        Key::C => 0x2e,
+        Key::V => 0x2f,
+        Key::B => 0x30,
+        Key::N => 0x31,
+        Key::M => 0x32,
+        Key::Comma => 0x33,
+        Key::Period => 0x34,
+        Key::Slash => 0x35,
+        Key::RightShift => 0x36,
+        Key::NumPadAsterisk => 0x37,
+        Key::LeftAlt => 0x38,
+        Key::Space => 0x39,
+        Key::CapsLock => 0x3a,
+        Key::F1 => 0x3b,
+        Key::F2 => 0x3c,
+        Key::F3 => 0x3d,
+        Key::F4 => 0x3e,
+        Key::F5 => 0x3f,
+        Key::F6 => 0x40,
+        Key::F7 => 0x41,
+        Key::F8 => 0x42,
+        Key::F9 => 0x43,
+        Key::F10 => 0x44,
+        Key::NumLock => 0x45,
+        Key::ScrollLock => 0x46,
+        Key::NumPad7 => 0x47,
+        Key::NumPad8 => 0x48,
+        Key::NumPad9 => 0x49,
+        Key::NumPadMinus => 0x4a,
+        Key::NumPad4 => 0x4b,
+        Key::NumPad5 => 0x4c,
+        Key::NumPad6 => 0x4d,
+        Key::NumPadPlus => 0x4e,
+        Key::NumPad1 => 0x4f,
+        Key::NumPad2 => 0x50,
+        Key::NumPad3 => 0x51,
+        Key::NumPad0 => 0x52,
+        Key::NumPadDot => 0x53,
+        Key::F11 => 0x57,
+        Key::F12 => 0x58,
+        Key::F13 => 0x5d,
+        Key::F14 => 0x5e,
+        Key::F15 => 0x5f,
+        Key::NumPadEnter => 0x9c,
+        Key::RightCtrl => 0x9d,
+        Key::NumPadSlash => 0xb5,
+        Key::RightAlt => 0xb8,
+        Key::Pause => 0xc6,
+        Key::Home => 0xc7,
+        Key::Up => 0xc8,
+        Key::PageUp => 0xc9,
+        Key::Left => 0xcb,
+        Key::Right => 0xcd,
+        Key::End => 0xcf,
+        Key::Down => 0xd0,
+        Key::PageDown => 0xd1,
+        Key::Insert => 0xd2,
+        Key::Delete => 0xd3,
+        Key::LeftSuper => 0xdb,
+        Key::RightSuper => 0xdc,
+        Key::Menu => 0xdd,
+        Key::Unknown | Key::Count => return None,
+    };
+    Some(qnum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_are_scaled_to_the_guest() {
+        assert_eq!(window_to_guest((100.0, 50.0), (1280, 800), (640, 400)), Some((50.0, 25.0)));
+    }
+
+    #[test]
+    fn letterbox_borders_are_skipped() {
+        // 640x400 in a 1280x1000 window: scaled by 2, 100 pixel bars above and below
+        assert_eq!(window_to_guest((10.0, 50.0), (1280, 1000), (640, 400)), None);
+        assert_eq!(window_to_guest((10.0, 150.0), (1280, 1000), (640, 400)), Some((5.0, 25.0)));
+        assert_eq!(window_to_guest((10.0, 950.0), (1280, 1000), (640, 400)), None);
+
+        // And pillarboxed: scaled by 1, 100 pixel bars left and right
+        assert_eq!(window_to_guest((50.0, 10.0), (840, 400), (640, 400)), None);
+        assert_eq!(window_to_guest((150.0, 10.0), (840, 400), (640, 400)), Some((50.0, 10.0)));
+    }
+
+    #[test]
+    fn no_guest_surface_yet() {
+        assert_eq!(window_to_guest((10.0, 10.0), (1280, 800), (0, 0)), None);
+    }
+
+    #[test]
+    fn keys_map_to_qnums() {
+        assert_eq!(qnum_from_key(Key::Escape), Some(0x01));
+        assert_eq!(qnum_from_key(Key::A), Some(0x1e));
+        assert_eq!(qnum_from_key(Key::LeftShift), Some(0x2a));
+        assert_eq!(qnum_from_key(Key::RightCtrl), Some(0x9d));
+        assert_eq!(qnum_from_key(Key::Unknown), None);
+    }
+
+    #[test]
+    fn keypad_is_distinct_from_the_main_block() {
+        assert_ne!(qnum_from_key(Key::NumPadEnter), qnum_from_key(Key::Enter));
+        assert_ne!(qnum_from_key(Key::NumPadSlash), qnum_from_key(Key::Slash));
+        assert_ne!(qnum_from_key(Key::NumPad8), qnum_from_key(Key::Up));
+        assert_ne!(qnum_from_key(Key::NumPadDot), qnum_from_key(Key::Delete));
+    }
+}
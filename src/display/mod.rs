@@ -1,9 +1,33 @@
 pub mod console;
+pub mod error;
+pub mod connect;
+pub mod qmp;
+pub mod chardev;
+pub mod vm;
 pub mod utils;
 pub mod console_listenner;
-mod mouse;
-mod keyboard;
+pub mod console_events;
+pub mod mouse;
+pub mod keyboard;
+pub mod touch;
+#[cfg(feature = "pixels")]
+pub mod keymap;
 pub mod console_handler;
+pub mod supervisor;
+pub mod cursor;
+pub mod dmabuf;
+pub mod drm_format;
+pub mod framebuffer;
+pub mod pixman_format;
+pub mod shared_map;
+pub mod clipboard;
+pub mod audio;
+pub mod audio_listener;
+pub mod audio_sink;
+pub mod audio_source;
+pub mod viewer;
+#[cfg(feature = "pixels")]
 pub mod pixels_window;
-mod minifb_window;
+#[cfg(feature = "minifb")]
+pub mod minifb_window;
 
@@ -1,33 +1,42 @@
-use std::error::Error;
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use pixels::{Pixels, SurfaceTexture};
-use tokio::sync::mpsc::{Receiver, Sender};
-use winit::dpi::LogicalSize;
-use winit::event::{Event, WindowEvent};
+use tokio::sync::mpsc::UnboundedSender;
+use winit::dpi::{LogicalSize, PhysicalSize};
+use winit::event::{DeviceEvent, ElementState, Event, KeyboardInput, ModifiersState, MouseScrollDelta, Touch, TouchPhase, VirtualKeyCode, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
-use winit::window::WindowBuilder;
-use crate::display::console::Console;
-use crate::display::console_handler::DisplayHandlers;
-use crate::display::utils::WindowCommand;
-
-pub async fn build_pixels_window(
-    sender: Sender<WindowCommand>,
-    mut receiver: Receiver<WindowCommand>,
-    console_handler: Arc<Result<Console, Box<dyn Error + Send + Sync>>>
-) {
-    let window_width = 400;
-    let window_height = 300 ad;
+use winit::window::{CursorGrabMode, Window, WindowBuilder};
+use tracing::{debug, error, info, trace, warn};
+use crate::display::cursor::{CursorMode, CursorState};
+use crate::display::framebuffer::{Framebuffer, Rect};
+use crate::display::keymap::qnum_from_winit;
+use crate::display::mouse::MouseButton;
+use crate::display::supervisor::ConnectionState;
+use crate::display::touch::TouchEventKind;
+use crate::display::utils::{ViewerOptions, WindowCommand};
+use crate::display::viewer::{dispatch_commands, DisplaySink, InputSource, Viewer};
+
+pub async fn build_pixels_window(viewer: Viewer, options: ViewerOptions) {
+    let Viewer { framebuffer, cursor, mut commands, input } = viewer;
+    let buffer_width = 400;
+    let buffer_height = 300;
 
     // Create an event loop
     let event_loop = EventLoop::new();
 
     // Create a window
-    let window = WindowBuilder::new()
-        .with_title("Pixels Example")
-        .with_inner_size(LogicalSize::new(window_width, window_height))
+    let window = match WindowBuilder::new()
+        .with_title(WINDOW_TITLE)
+        .with_inner_size(LogicalSize::new(buffer_width, buffer_height))
         .build(&event_loop)
-        .unwrap();
+    {
+        Ok(window) => window,
+        Err(e) => {
+            error!("Failed to create window: {}", e);
+            return;
+        }
+    };
 
     // Create a surface texture
     let window_size = window.inner_size();
@@ -36,76 +45,631 @@ pub async fn build_pixels_window(
     );
 
     // Create a Pixels instance
-    let mut pixels = Pixels::new(window_width, window_height, surface_texture).unwrap();
-
-    // Connect to the console and register the DBus listener
-    let sender_clone = sender.clone();
-    let cloned_console_handler_2 = Arc::clone(&console_handler);
-    tokio::spawn(async move {
-        match cloned_console_handler_2.as_ref() {
-            Ok(console) => {
-                println!("Connected to console");
-                let handlers = DisplayHandlers::new(sender_clone);
-                let _ = console.register_listener(handlers).await.unwrap();
-            }
-            Err(e) => {
-                panic!("Error: {}", e);
+    let pixels = match Pixels::new(buffer_width, buffer_height, surface_texture) {
+        Ok(pixels) => pixels,
+        Err(e) => {
+            error!("Failed to create the pixels surface: {}", e);
+            return;
+        }
+    };
+
+    let native_cursor = match options.cursor_mode {
+        CursorMode::Composite => None,
+        CursorMode::Native => {
+            let native_cursor = NativeCursor::new(&window);
+            if native_cursor.is_none() {
+                warn!("Native cursors need X11 and libXcursor, compositing the cursor instead");
             }
+            native_cursor
         }
-    });
+    };
+
+    // Window size waiting to be sent to the guest once resizing settles
+    let pending_resize = options.auto_resize.then(|| (window.inner_size(), Instant::now()));
 
-    // Main event loop timer
-    let mut last_update = Instant::now();
+    let mut pixels_window = PixelsWindow {
+        window,
+        pixels,
+        framebuffer,
+        cursor,
+        options,
+        buffer_width,
+        buffer_height,
+        pressed_keys: HashSet::new(),
+        modifiers: ModifiersState::empty(),
+        swallowed_key: None,
+        absolute: true,
+        grabbed: false,
+        pending_resize,
+        pending_input: Vec::new(),
+        damaged: false,
+        wheel: WheelSteps::default(),
+        native_cursor,
+    };
 
     // Event loop to keep the window open and render the pixels
     event_loop.run(move |event, _, control_flow| {
-        println!("Received event: {:?}", event);
+        trace!("Received event: {:?}", event);
+        pixels_window.handle_event(event, control_flow);
+        pixels_window.poll_input(&input);
+        dispatch_commands(&mut commands, &mut pixels_window);
+        if std::mem::take(&mut pixels_window.damaged) {
+            pixels_window.window.request_redraw();
+        }
+        // Nothing wakes the loop up when the guest draws, so check for commands every tick
+        if *control_flow != ControlFlow::Exit {
+            *control_flow = ControlFlow::WaitUntil(Instant::now() + COMMAND_POLL_INTERVAL);
+        }
+    });
+}
+
+/// winit window presenting the framebuffer through a pixels buffer the size of the guest.
+struct PixelsWindow {
+    window: Window,
+    pixels: Pixels,
+    framebuffer: Arc<Mutex<Framebuffer>>,
+    cursor: Arc<Mutex<CursorState>>,
+    options: ViewerOptions,
+    buffer_width: u32,
+    buffer_height: u32,
+    /// Keys held down in the guest, released if the window loses focus
+    pressed_keys: HashSet<u32>,
+    modifiers: ModifiersState,
+    /// Key of the release hotkey, whose release isn't sent either
+    swallowed_key: Option<VirtualKeyCode>,
+    /// In relative mode the host pointer is grabbed and motion is sent as deltas
+    absolute: bool,
+    grabbed: bool,
+    /// Window size waiting to be sent to the guest once resizing settles
+    pending_resize: Option<(PhysicalSize<u32>, Instant)>,
+    /// Input of the events handled since the last `poll_input`
+    pending_input: Vec<WindowCommand>,
+    /// The frame changed since it was last rendered
+    damaged: bool,
+    wheel: WheelSteps,
+    /// Host pointer showing the guest cursor, `None` when it is composited
+    native_cursor: Option<NativeCursor>,
+}
+
+impl PixelsWindow {
+    fn handle_event(&mut self, event: Event<()>, control_flow: &mut ControlFlow) {
         match event {
             Event::WindowEvent { event, .. } => match event {
                 WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
                 WindowEvent::Resized(size) => {
-                    let _ = pixels.resize_surface(size.width, size.height);
+                    let _ = self.pixels.resize_surface(size.width, size.height);
+                    self.damaged = true;
+                    if self.options.auto_resize {
+                        self.pending_resize = Some((size, Instant::now()));
+                    }
+                }
+                WindowEvent::ModifiersChanged(state) => self.modifiers = state,
+                WindowEvent::KeyboardInput {
+                    input: KeyboardInput { scancode, state, virtual_keycode, .. },
+                    ..
+                } => {
+                    if self.grabbed
+                        && state == ElementState::Pressed
+                        && self.options.release_hotkey.matches(self.modifiers, virtual_keycode)
+                    {
+                        self.set_grab(false);
+                        self.swallowed_key = virtual_keycode;
+                        return;
+                    }
+                    if state == ElementState::Released
+                        && virtual_keycode.is_some()
+                        && self.swallowed_key == virtual_keycode
+                    {
+                        self.swallowed_key = None;
+                        return;
+                    }
+                    if let Some(qnum) = qnum_from_winit(scancode, virtual_keycode) {
+                        let command = match state {
+                            ElementState::Pressed => {
+                                self.pressed_keys.insert(qnum);
+                                WindowCommand::KeyPress(qnum)
+                            }
+                            ElementState::Released => {
+                                self.pressed_keys.remove(&qnum);
+                                WindowCommand::KeyRelease(qnum)
+                            }
+                        };
+                        self.pending_input.push(command);
+                    }
+                }
+                WindowEvent::CursorMoved { position, .. } if self.absolute => {
+                    let (x, y) = self.window_to_guest(position.x as f32, position.y as f32);
+                    self.pending_input.push(WindowCommand::MouseMove(x, y));
+                }
+                WindowEvent::MouseInput { state, button, .. } => {
+                    let button = match button {
+                        winit::event::MouseButton::Left => MouseButton::Left,
+                        winit::event::MouseButton::Middle => MouseButton::Middle,
+                        winit::event::MouseButton::Right => MouseButton::Right,
+                        // X11 numbering of the back and forward buttons
+                        winit::event::MouseButton::Other(8) => MouseButton::Side,
+                        winit::event::MouseButton::Other(9) => MouseButton::Extra,
+                        winit::event::MouseButton::Other(_) => return,
+                    };
+                    // The click that grabs the pointer isn't sent to the guest
+                    if !self.absolute && !self.grabbed {
+                        if state == ElementState::Pressed {
+                            self.set_grab(true);
+                        }
+                        return;
+                    }
+                    let command = match state {
+                        ElementState::Pressed => WindowCommand::MousePress(button),
+                        ElementState::Released => WindowCommand::MouseRelease(button),
+                    };
+                    self.pending_input.push(command);
+                }
+                WindowEvent::MouseWheel { delta, .. } => {
+                    // Horizontal scrolling has no button in the guest
+                    let lines = match delta {
+                        MouseScrollDelta::LineDelta(_, y) => y as f64,
+                        MouseScrollDelta::PixelDelta(position) => position.y / SCROLL_LINE_HEIGHT,
+                    };
+                    let clicks = self.wheel.take(lines);
+                    let button = if clicks > 0 { MouseButton::WheelUp } else { MouseButton::WheelDown };
+                    for _ in 0..clicks.abs() {
+                        self.pending_input.push(WindowCommand::MousePress(button));
+                        self.pending_input.push(WindowCommand::MouseRelease(button));
+                    }
+                }
+                WindowEvent::Touch(Touch { phase, location, id, .. }) => {
+                    let kind = match phase {
+                        TouchPhase::Started => TouchEventKind::Begin,
+                        TouchPhase::Moved => TouchEventKind::Update,
+                        TouchPhase::Ended => TouchEventKind::End,
+                        TouchPhase::Cancelled => TouchEventKind::Cancel,
+                    };
+                    let (x, y) = self.window_to_guest(location.x as f32, location.y as f32);
+                    self.pending_input.push(WindowCommand::Touch(kind, id, x as f64, y as f64));
+                }
+                WindowEvent::Focused(false) => {
+                    for qnum in self.pressed_keys.drain() {
+                        self.pending_input.push(WindowCommand::KeyRelease(qnum));
+                    }
                 }
                 _ => (),
             },
+            Event::DeviceEvent { event: DeviceEvent::MouseMotion { delta }, .. } if self.grabbed => {
+                self.pending_input.push(WindowCommand::MouseRelMove(delta.0, delta.1));
+            }
             Event::RedrawRequested(_) => {
-                if pixels.render().is_err() {
+                if let Err(e) = self.pixels.render() {
+                    error!("Failed to render: {}", e);
                     *control_flow = ControlFlow::Exit;
-                    return;
                 }
             }
             _ => (),
         }
+    }
 
-        if let Ok(command) = receiver.try_recv() {
-            match command {
-                WindowCommand::Update(buffer, width, height) => { },
-                WindowCommand::UpdateDMABUF(buffer) => {
-                    if last_update.elapsed() >= Duration::from_millis(16) { // ~60 FPS
-                        update_frame_from_dmabuf(pixels.frame_mut(), &buffer);
-                        window.request_redraw();
-                        last_update = Instant::now();
-                    }
+    /// Maps a position in the window to the guest display, undoing the scaling and letterboxing
+    /// of the pixels buffer.
+    fn window_to_guest(&self, x: f32, y: f32) -> (f32, f32) {
+        let position = self
+            .pixels
+            .window_pos_to_pixel((x, y))
+            .unwrap_or_else(|position| self.pixels.clamp_pixel_pos(position));
+        let framebuffer = self.framebuffer.lock().unwrap();
+        buffer_to_guest(
+            position,
+            (self.buffer_width, self.buffer_height),
+            (framebuffer.width, framebuffer.height)
+        )
+    }
+
+    /// Grabs and hides the host pointer for the relative mouse mode, or gives it back.
+    fn set_grab(&mut self, grab: bool) {
+        set_grab(&self.window, grab);
+        self.grabbed = grab;
+
+        // A hidden host pointer can't show the cursor, the frame has to
+        if self.native_cursor.is_some() {
+            self.redraw_cursor();
+            self.update_native_cursor();
+        }
+    }
+
+    /// Whether the cursor is blended into the frame rather than shown as the host pointer.
+    fn composites_cursor(&self) -> bool {
+        self.native_cursor.is_none() || self.grabbed
+    }
+
+    /// Shows the guest cursor as the host pointer, or hides it with the guest one.
+    fn update_native_cursor(&mut self) {
+        if self.grabbed {
+            return;
+        }
+        let Some(native_cursor) = self.native_cursor.as_mut() else {
+            return;
+        };
+
+        let cursor = self.cursor.lock().unwrap();
+        if cursor.visible && !cursor.data.is_empty() {
+            // winit puts its own pointer back when showing it
+            self.window.set_cursor_visible(true);
+            native_cursor.define(&cursor);
+        } else {
+            self.window.set_cursor_visible(false);
+        }
+    }
+
+    /// Redraws the area under the cursor, e.g. when it starts or stops being composited.
+    fn redraw_cursor(&mut self) {
+        let rect = {
+            let framebuffer = self.framebuffer.lock().unwrap();
+            let cursor = self.cursor.lock().unwrap();
+            cursor.rect(framebuffer.width, framebuffer.height)
+        };
+        if let Some(rect) = rect {
+            self.redraw(rect);
+        }
+    }
+
+    /// Redraws `rect` of the pixels buffer from the framebuffer, with the cursor over it unless
+    /// it is shown natively.
+    fn redraw(&mut self, rect: Rect) {
+        let composite = self.composites_cursor();
+        // Same lock order as the handler: framebuffer, then cursor
+        let framebuffer = self.framebuffer.lock().unwrap();
+        let cursor = self.cursor.lock().unwrap();
+        copy_damage_to_frame(
+            self.pixels.frame_mut(),
+            self.buffer_width,
+            self.buffer_height,
+            &framebuffer,
+            composite.then_some(&*cursor),
+            rect
+        );
+        self.damaged = true;
+    }
+}
+
+impl DisplaySink for PixelsWindow {
+    fn on_resize(&mut self, width: u32, height: u32) {
+        // New guest resolution, reallocate the buffer and redraw all of it
+        if self.pixels.resize_buffer(width, height).is_err() {
+            warn!("Failed to resize the pixels buffer to {}x{}", width, height);
+            return;
+        }
+        self.buffer_width = width;
+        self.buffer_height = height;
+        // Follow the guest, unless it's the guest following the window
+        if !self.options.auto_resize {
+            self.window.set_inner_size(LogicalSize::new(width, height));
+        }
+        self.redraw(Rect { x: 0, y: 0, width, height });
+    }
+
+    fn on_damage(&mut self, rect: Rect) {
+        self.redraw(rect);
+    }
+
+    fn on_cursor(&mut self, rects: Vec<Rect>) {
+        if !self.composites_cursor() {
+            self.update_native_cursor();
+            return;
+        }
+        for rect in rects {
+            self.redraw(rect);
+        }
+    }
+
+    fn on_state(&mut self, state: ConnectionState) {
+        info!("Connection state: {:?}", state);
+        match state {
+            ConnectionState::Connected => self.window.set_title(WINDOW_TITLE),
+            ConnectionState::Disconnected => {
+                self.window.set_title(&format!("{} (disconnected)", WINDOW_TITLE));
+                // Whatever was held went away with the old guest connection
+                self.pressed_keys.clear();
+                if self.grabbed {
+                    self.set_grab(false);
                 }
-                _ => {}
+            }
+            ConnectionState::Reconnecting { attempt, delay } => {
+                self.window.set_title(&format!(
+                    "{} (reconnecting in {:.1}s, attempt {})",
+                    WINDOW_TITLE,
+                    delay.as_secs_f32(),
+                    attempt
+                ));
             }
         }
+    }
 
-        window.request_redraw();
-    });
+    fn on_absolute(&mut self, absolute: bool) {
+        debug!("Guest mouse is {}", if absolute { "absolute" } else { "relative" });
+        self.absolute = absolute;
+        if absolute && self.grabbed {
+            self.set_grab(false);
+        }
+    }
+}
+
+impl InputSource for PixelsWindow {
+    fn poll_input(&mut self, input: &UnboundedSender<WindowCommand>) {
+        if let Some((size, since)) = self.pending_resize {
+            if since.elapsed() >= RESIZE_DEBOUNCE {
+                self.pending_resize = None;
+                if size.width > 0 && size.height > 0 {
+                    let (width_mm, height_mm) = size_mm(size, monitor_dpi(&self.window));
+                    self.pending_input.push(
+                        WindowCommand::UiInfo(width_mm, height_mm, size.width, size.height)
+                    );
+                }
+            }
+        }
+
+        // A dropped release would leave the key stuck in the guest, so nothing is dropped here
+        for command in self.pending_input.drain(..) {
+            let _ = input.send(command);
+        }
+    }
 }
 
-fn update_frame_from_dmabuf(frame: &mut [u8], buffer: &[u8]) {
-    println!("Updating the pixels frame from DMABUF");
-    // Manipulate the pixel buffer
-    // for (i, pixel) in frame.chunks_exact_mut(4).enumerate() {
-    //     let x = (i % 340) as u8;
-    //     let y = (i / 220) as u8;
-    //     pixel[0] = x; // R
-    //     pixel[1] = y; // G
-    //     pixel[2] = 0; // B
-    //     pixel[3] = 255; // A
-    // }
-
-    frame.copy_from_slice(&buffer);
-}
\ No newline at end of file
+const WINDOW_TITLE: &str = "Qemu Display Buffer";
+
+/// How often the commands of the handler and the pending input are checked while the window
+/// has no events, about once a frame.
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Pixels of a trackpad scroll making up one wheel click.
+const SCROLL_LINE_HEIGHT: f64 = 40.0;
+
+/// Turns the scrolled lines, fractional with trackpads and smooth wheels, into whole wheel
+/// clicks, carrying the remainder to the next scroll.
+#[derive(Debug, Default)]
+struct WheelSteps {
+    lines: f64,
+}
+
+impl WheelSteps {
+    /// Clicks to send for `lines` more lines, positive up.
+    fn take(&mut self, lines: f64) -> i32 {
+        // Turning back starts over rather than first undoing what is left the other way
+        if lines * self.lines < 0.0 {
+            self.lines = 0.0;
+        }
+        self.lines += lines;
+        let clicks = self.lines.trunc();
+        self.lines -= clicks;
+        clicks as i32
+    }
+}
+
+/// How long the window size has to stay the same before the guest is asked to follow it.
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Physical size of the window in millimeters on a monitor of `dpi` pixels per inch.
+fn size_mm(size: PhysicalSize<u32>, dpi: f64) -> (u16, u16) {
+    let to_mm = |pixels: u32| (pixels as f64 * 25.4 / dpi).round().min(u16::MAX as f64) as u16;
+    (to_mm(size.width), to_mm(size.height))
+}
+
+/// Pixels per inch of the monitor showing `window`, from its physical size when the platform
+/// reports it and 96 per unit of scale otherwise.
+fn monitor_dpi(window: &Window) -> f64 {
+    #[cfg(all(unix, not(target_os = "macos")))]
+    if let Some(dpi) = xrandr_monitor_dpi(window) {
+        return dpi;
+    }
+
+    96.0 * window.scale_factor()
+}
+
+/// DPI from the size XRandR reports for the monitor the window is on, `None` off X11 or when
+/// the monitor doesn't say how big it is.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn xrandr_monitor_dpi(window: &Window) -> Option<f64> {
+    use winit::platform::x11::WindowExtX11;
+    use x11_dl::xlib::{Display, True, Xlib};
+    use x11_dl::xrandr::Xrandr;
+
+    let display = window.xlib_display()? as *mut Display;
+    let screen = window.xlib_screen_id()?;
+    let position = window.current_monitor()?.position();
+    let xlib = Xlib::open().ok()?;
+    let xrandr = Xrandr::open().ok()?;
+
+    unsafe {
+        let root = (xlib.XRootWindow)(display, screen);
+        let mut count = 0;
+        let monitors = (xrandr.XRRGetMonitors)(display, root, True, &mut count);
+        if monitors.is_null() {
+            return None;
+        }
+        let dpi = std::slice::from_raw_parts(monitors, count.max(0) as usize)
+            .iter()
+            .find(|monitor| monitor.x == position.x && monitor.y == position.y)
+            .filter(|monitor| monitor.mwidth > 0)
+            .map(|monitor| monitor.width as f64 * 25.4 / monitor.mwidth as f64);
+        (xrandr.XRRFreeMonitors)(monitors);
+        dpi
+    }
+}
+
+/// The guest cursor bitmap as the X11 pointer of the window, through libXcursor.
+#[cfg(all(unix, not(target_os = "macos")))]
+struct NativeCursor {
+    xlib: x11_dl::xlib::Xlib,
+    xcursor: x11_dl::xcursor::Xcursor,
+    display: *mut x11_dl::xlib::Display,
+    window: std::os::raw::c_ulong,
+    /// X cursor built from the bitmap of `serial`, 0 before the first one
+    cursor: std::os::raw::c_ulong,
+    serial: u64,
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+impl NativeCursor {
+    /// `None` off X11 or without libXcursor.
+    fn new(window: &Window) -> Option<Self> {
+        use winit::platform::x11::WindowExtX11;
+
+        Some(Self {
+            display: window.xlib_display()? as *mut x11_dl::xlib::Display,
+            window: window.xlib_window()?,
+            xlib: x11_dl::xlib::Xlib::open().ok()?,
+            xcursor: x11_dl::xcursor::Xcursor::open().ok()?,
+            cursor: 0,
+            serial: 0,
+        })
+    }
+
+    /// Sets the pointer of the window to the bitmap of `state`, built again only when it changed.
+    fn define(&mut self, state: &CursorState) {
+        if self.cursor == 0 || self.serial != state.serial {
+            let Some(cursor) = self.load(state) else {
+                warn!("Failed to build a {}x{} native cursor", state.width, state.height);
+                return;
+            };
+            self.free();
+            self.cursor = cursor;
+            self.serial = state.serial;
+        }
+
+        unsafe {
+            (self.xlib.XDefineCursor)(self.display, self.window, self.cursor);
+            (self.xlib.XFlush)(self.display);
+        }
+    }
+
+    fn load(&self, state: &CursorState) -> Option<std::os::raw::c_ulong> {
+        let len = state.width as usize * state.height as usize;
+        if len == 0 || state.data.len() < len {
+            return None;
+        }
+
+        unsafe {
+            let image = (self.xcursor.XcursorImageCreate)(state.width.try_into().ok()?, state.height.try_into().ok()?);
+            if image.is_null() {
+                return None;
+            }
+            // The hotspot has to be on the bitmap
+            (*image).xhot = state.hot_x.clamp(0, state.width as i32 - 1) as u32;
+            (*image).yhot = state.hot_y.clamp(0, state.height as i32 - 1) as u32;
+            let pixels = std::slice::from_raw_parts_mut((*image).pixels, len);
+            for (pixel, premultiplied) in pixels.iter_mut().zip(state.premultiplied()) {
+                *pixel = premultiplied;
+            }
+
+            let cursor = (self.xcursor.XcursorImageLoadCursor)(self.display, image);
+            (self.xcursor.XcursorImageDestroy)(image);
+            (cursor != 0).then_some(cursor)
+        }
+    }
+
+    fn free(&mut self) {
+        if self.cursor != 0 {
+            unsafe {
+                (self.xlib.XFreeCursor)(self.display, self.cursor);
+            }
+            self.cursor = 0;
+        }
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+impl Drop for NativeCursor {
+    fn drop(&mut self) {
+        self.free();
+    }
+}
+
+/// Native cursors are only built on X11 so far.
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+struct NativeCursor;
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+impl NativeCursor {
+    fn new(_window: &Window) -> Option<Self> {
+        None
+    }
+
+    fn define(&mut self, _state: &CursorState) {}
+}
+
+/// Grabs and hides the host pointer for the relative mouse mode, or gives it back.
+fn set_grab(window: &Window, grab: bool) {
+    if grab {
+        // Not every platform can lock the pointer in place, confining it is close enough
+        let result = window
+            .set_cursor_grab(CursorGrabMode::Locked)
+            .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined));
+        if let Err(e) = result {
+            warn!("Failed to grab the pointer: {}", e);
+        }
+    } else {
+        let _ = window.set_cursor_grab(CursorGrabMode::None);
+    }
+    window.set_cursor_visible(!grab);
+}
+
+/// Scales a position in the pixels buffer to the guest display, which can differ in size.
+fn buffer_to_guest(position: (usize, usize), buffer: (u32, u32), guest: (u32, u32)) -> (f32, f32) {
+    if buffer.0 == 0 || buffer.1 == 0 {
+        return (0.0, 0.0);
+    }
+
+    (
+        position.0 as f32 * guest.0 as f32 / buffer.0 as f32,
+        position.1 as f32 * guest.1 as f32 / buffer.1 as f32,
+    )
+}
+
+/// Copies the damaged region of the framebuffer into the RGBA pixels frame, clipped to both,
+/// with the cursor blended over it when given.
+fn copy_damage_to_frame(
+    frame: &mut [u8],
+    frame_width: u32,
+    frame_height: u32,
+    framebuffer: &Framebuffer,
+    cursor: Option<&CursorState>,
+    rect: Rect
+) {
+    let x1 = (rect.x + rect.width).min(frame_width).min(framebuffer.width);
+    let y1 = (rect.y + rect.height).min(frame_height).min(framebuffer.height);
+
+    for y in rect.y..y1 {
+        for x in rect.x..x1 {
+            let mut pixel = framebuffer.data[(y * framebuffer.width + x) as usize];
+            if let Some(cursor) = cursor {
+                pixel = cursor.blend(x, y, pixel);
+            }
+            let offset = ((y * frame_width + x) * 4) as usize;
+            frame[offset] = (pixel >> 16) as u8;     // R
+            frame[offset + 1] = (pixel >> 8) as u8;  // G
+            frame[offset + 2] = pixel as u8;         // B
+            frame[offset + 3] = (pixel >> 24) as u8; // A
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wheel_steps_carry_fractions() {
+        let mut wheel = WheelSteps::default();
+        assert_eq!(wheel.take(0.0), 0);
+        assert_eq!(wheel.take(0.5), 0);
+        assert_eq!(wheel.take(0.75), 1);
+        assert_eq!(wheel.take(2.0), 2);
+        // The quarter line left over is dropped when turning back
+        assert_eq!(wheel.take(-0.5), 0);
+        assert_eq!(wheel.take(-0.5), -1);
+        assert_eq!(wheel.take(-3.0), -3);
+    }
+
+    #[test]
+    fn size_mm_follows_the_dpi() {
+        assert_eq!(size_mm(PhysicalSize::new(960, 480), 96.0), (254, 127));
+        assert_eq!(size_mm(PhysicalSize::new(960, 480), 192.0), (127, 64));
+    }
+}
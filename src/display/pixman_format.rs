@@ -0,0 +1,195 @@
+// Pixel types from pixman.h
+const PIXMAN_TYPE_ARGB: u32 = 2;
+const PIXMAN_TYPE_ABGR: u32 = 3;
+const PIXMAN_TYPE_BGRA: u32 = 8;
+const PIXMAN_TYPE_RGBA: u32 = 9;
+
+const fn pixman_format(bpp: u32, type_: u32, a: u32, r: u32, g: u32, b: u32) -> u32 {
+    bpp << 24 | type_ << 16 | a << 12 | r << 8 | g << 4 | b
+}
+
+/// Pixman formats QEMU uses for `Scanout`/`Update` surfaces, named as in pixman.h.
+///
+/// Formats are defined on the host-endian pixel value, so data is decoded as little-endian.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PixmanFormat {
+    A8r8g8b8,
+    X8r8g8b8,
+    A8b8g8r8,
+    X8b8g8r8,
+    B8g8r8a8,
+    B8g8r8x8,
+    R8g8b8a8,
+    R8g8b8x8,
+    R8g8b8,
+    B8g8r8,
+    R5g6b5,
+    B5g6r5,
+    A1r5g5b5,
+    X1r5g5b5,
+}
+
+impl PixmanFormat {
+    pub const ALL: [PixmanFormat; 14] = [
+        PixmanFormat::A8r8g8b8,
+        PixmanFormat::X8r8g8b8,
+        PixmanFormat::A8b8g8r8,
+        PixmanFormat::X8b8g8r8,
+        PixmanFormat::B8g8r8a8,
+        PixmanFormat::B8g8r8x8,
+        PixmanFormat::R8g8b8a8,
+        PixmanFormat::R8g8b8x8,
+        PixmanFormat::R8g8b8,
+        PixmanFormat::B8g8r8,
+        PixmanFormat::R5g6b5,
+        PixmanFormat::B5g6r5,
+        PixmanFormat::A1r5g5b5,
+        PixmanFormat::X1r5g5b5,
+    ];
+
+    pub fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.code() == code)
+    }
+
+    /// The `pixman_format_code_t` value QEMU sends for this format.
+    pub fn code(&self) -> u32 {
+        let (bpp, type_, a, r, g, b) = self.layout();
+        pixman_format(bpp, type_, a, r, g, b)
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        (self.layout().0 / 8) as usize
+    }
+
+    /// (bpp, type, a, r, g, b) as passed to PIXMAN_FORMAT.
+    fn layout(&self) -> (u32, u32, u32, u32, u32, u32) {
+        match self {
+            PixmanFormat::A8r8g8b8 => (32, PIXMAN_TYPE_ARGB, 8, 8, 8, 8),
+            PixmanFormat::X8r8g8b8 => (32, PIXMAN_TYPE_ARGB, 0, 8, 8, 8),
+            PixmanFormat::A8b8g8r8 => (32, PIXMAN_TYPE_ABGR, 8, 8, 8, 8),
+            PixmanFormat::X8b8g8r8 => (32, PIXMAN_TYPE_ABGR, 0, 8, 8, 8),
+            PixmanFormat::B8g8r8a8 => (32, PIXMAN_TYPE_BGRA, 8, 8, 8, 8),
+            PixmanFormat::B8g8r8x8 => (32, PIXMAN_TYPE_BGRA, 0, 8, 8, 8),
+            PixmanFormat::R8g8b8a8 => (32, PIXMAN_TYPE_RGBA, 8, 8, 8, 8),
+            PixmanFormat::R8g8b8x8 => (32, PIXMAN_TYPE_RGBA, 0, 8, 8, 8),
+            PixmanFormat::R8g8b8 => (24, PIXMAN_TYPE_ARGB, 0, 8, 8, 8),
+            PixmanFormat::B8g8r8 => (24, PIXMAN_TYPE_ABGR, 0, 8, 8, 8),
+            PixmanFormat::R5g6b5 => (16, PIXMAN_TYPE_ARGB, 0, 5, 6, 5),
+            PixmanFormat::B5g6r5 => (16, PIXMAN_TYPE_ABGR, 0, 5, 6, 5),
+            PixmanFormat::A1r5g5b5 => (16, PIXMAN_TYPE_ARGB, 1, 5, 5, 5),
+            PixmanFormat::X1r5g5b5 => (16, PIXMAN_TYPE_ARGB, 0, 5, 5, 5),
+        }
+    }
+
+    /// Decodes one pixel into the canonical `0xAARRGGBB` frame format.
+    pub fn to_argb(&self, bytes: &[u8]) -> u32 {
+        let (bpp, type_, a, r, g, b) = self.layout();
+
+        let mut value = 0u32;
+        for (i, byte) in bytes[..(bpp / 8) as usize].iter().enumerate() {
+            value |= (*byte as u32) << (i * 8);
+        }
+
+        // Bit offset of each channel, from pixman's PIXMAN_FORMAT_* shifts
+        let (a_shift, r_shift, g_shift, b_shift) = match type_ {
+            PIXMAN_TYPE_ARGB => (r + g + b, g + b, b, 0),
+            PIXMAN_TYPE_ABGR => (b + g + r, 0, r, g + r),
+            PIXMAN_TYPE_BGRA => (0, bpp - b - g - r, bpp - b - g, bpp - b),
+            PIXMAN_TYPE_RGBA => (0, bpp - r, bpp - r - g, bpp - r - g - b),
+            _ => unreachable!(),
+        };
+
+        let alpha = if a == 0 { 0xff } else { expand(value >> a_shift, a) };
+        alpha << 24
+            | expand(value >> r_shift, r) << 16
+            | expand(value >> g_shift, g) << 8
+            | expand(value >> b_shift, b)
+    }
+}
+
+/// Scales a `bits` wide channel to 8 bits.
+fn expand(value: u32, bits: u32) -> u32 {
+    let max = (1 << bits) - 1;
+    ((value & max) * 0xff + max / 2) / max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_match_pixman() {
+        let codes = [
+            (PixmanFormat::A8r8g8b8, 0x20028888),
+            (PixmanFormat::X8r8g8b8, 0x20020888),
+            (PixmanFormat::A8b8g8r8, 0x20038888),
+            (PixmanFormat::X8b8g8r8, 0x20030888),
+            (PixmanFormat::B8g8r8a8, 0x20088888),
+            (PixmanFormat::B8g8r8x8, 0x20080888),
+            (PixmanFormat::R8g8b8a8, 0x20098888),
+            (PixmanFormat::R8g8b8x8, 0x20090888),
+            (PixmanFormat::R8g8b8, 0x18020888),
+            (PixmanFormat::B8g8r8, 0x18030888),
+            (PixmanFormat::R5g6b5, 0x10020565),
+            (PixmanFormat::B5g6r5, 0x10030565),
+            (PixmanFormat::A1r5g5b5, 0x10021555),
+            (PixmanFormat::X1r5g5b5, 0x10020555),
+        ];
+
+        assert_eq!(codes.len(), PixmanFormat::ALL.len());
+        for (format, code) in codes {
+            assert_eq!(format.code(), code, "{:?}", format);
+            assert_eq!(PixmanFormat::from_code(code), Some(format));
+        }
+        assert_eq!(PixmanFormat::from_code(0), None);
+    }
+
+    #[test]
+    fn decodes_32bpp() {
+        // Little-endian bytes of a pixel with r = 0x11, g = 0x22, b = 0x33, a = 0x44
+        let pixels = [
+            (PixmanFormat::A8r8g8b8, [0x33, 0x22, 0x11, 0x44], 0x44112233),
+            (PixmanFormat::X8r8g8b8, [0x33, 0x22, 0x11, 0x44], 0xff112233),
+            (PixmanFormat::A8b8g8r8, [0x11, 0x22, 0x33, 0x44], 0x44112233),
+            (PixmanFormat::X8b8g8r8, [0x11, 0x22, 0x33, 0x44], 0xff112233),
+            (PixmanFormat::B8g8r8a8, [0x44, 0x11, 0x22, 0x33], 0x44112233),
+            (PixmanFormat::B8g8r8x8, [0x44, 0x11, 0x22, 0x33], 0xff112233),
+            (PixmanFormat::R8g8b8a8, [0x44, 0x33, 0x22, 0x11], 0x44112233),
+            (PixmanFormat::R8g8b8x8, [0x44, 0x33, 0x22, 0x11], 0xff112233),
+        ];
+
+        for (format, bytes, argb) in pixels {
+            assert_eq!(format.bytes_per_pixel(), 4);
+            assert_eq!(format.to_argb(&bytes), argb, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn decodes_24bpp() {
+        assert_eq!(PixmanFormat::R8g8b8.bytes_per_pixel(), 3);
+        assert_eq!(PixmanFormat::R8g8b8.to_argb(&[0x33, 0x22, 0x11]), 0xff112233);
+        assert_eq!(PixmanFormat::B8g8r8.bytes_per_pixel(), 3);
+        assert_eq!(PixmanFormat::B8g8r8.to_argb(&[0x11, 0x22, 0x33]), 0xff112233);
+    }
+
+    #[test]
+    fn decodes_16bpp() {
+        // Pure red, green and blue, then white
+        let pixels = [
+            (PixmanFormat::R5g6b5, 0xf800, 0xffff0000),
+            (PixmanFormat::R5g6b5, 0x07e0, 0xff00ff00),
+            (PixmanFormat::R5g6b5, 0x001f, 0xff0000ff),
+            (PixmanFormat::B5g6r5, 0x001f, 0xffff0000),
+            (PixmanFormat::B5g6r5, 0xf800, 0xff0000ff),
+            (PixmanFormat::A1r5g5b5, 0xfc00, 0xffff0000),
+            (PixmanFormat::A1r5g5b5, 0x7c00, 0x00ff0000),
+            (PixmanFormat::X1r5g5b5, 0x7fff, 0xffffffff),
+            (PixmanFormat::X1r5g5b5, 0x03e0, 0xff00ff00),
+        ];
+
+        for (format, value, argb) in pixels {
+            assert_eq!(format.bytes_per_pixel(), 2);
+            assert_eq!(format.to_argb(&u16::to_le_bytes(value)), argb, "{:?} {:#x}", format, value);
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest};
+use tokio::net::UnixStream;
+use tracing::debug;
+use crate::display::error::DisplayError;
+
+/// An asynchronous event from QEMU, like `SHUTDOWN` or `RESET`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QmpEvent {
+    pub event: String,
+    pub data: Value,
+    pub timestamp: Value,
+}
+
+/// Client of the QEMU Machine Protocol on a unix socket, e.g. `-qmp unix:/tmp/qmp.sock,server`.
+#[derive(Debug)]
+pub struct QmpClient {
+    stream: UnixStream,
+    buffer: Vec<u8>,
+    /// Events received while waiting for a command response
+    events: VecDeque<QmpEvent>,
+    next_id: u64,
+    /// The `QMP` object of the greeting, with the QEMU version and capabilities
+    pub greeting: Value,
+}
+
+impl QmpClient {
+    /// Connects and negotiates capabilities, leaving the client in command mode.
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self, DisplayError> {
+        let stream = UnixStream::connect(path).await?;
+        let mut client = Self {
+            stream,
+            buffer: Vec::new(),
+            events: VecDeque::new(),
+            next_id: 0,
+            greeting: Value::Null,
+        };
+
+        let greeting = client.read_message().await?;
+        client.greeting = match greeting.get("QMP") {
+            Some(qmp) => qmp.clone(),
+            None => return Err(DisplayError::Protocol(format!("Unexpected QMP greeting: {}", greeting))),
+        };
+        client.execute("qmp_capabilities", None).await?;
+
+        Ok(client)
+    }
+
+    /// Runs a command and returns its `return` value.
+    pub async fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value, DisplayError> {
+        let (id, request) = self.request(command, arguments);
+        self.stream.write_all(&request).await?;
+        self.response(id).await
+    }
+
+    /// Like `execute`, passing `fd` along with the command, as `getfd` and `add-fd` expect.
+    pub async fn execute_with_fd(
+        &mut self,
+        command: &str,
+        arguments: Option<Value>,
+        fd: RawFd,
+    ) -> Result<Value, DisplayError> {
+        let (id, request) = self.request(command, arguments);
+
+        let sent = loop {
+            self.stream.writable().await?;
+            let socket = self.stream.as_raw_fd();
+            match self.stream.try_io(Interest::WRITABLE, || send_with_fd(socket, &request, fd)) {
+                Ok(sent) => break sent,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        // The fd went with the first bytes, the rest of the command can follow normally
+        self.stream.write_all(&request[sent..]).await?;
+
+        self.response(id).await
+    }
+
+    /// Waits for the next event, starting with the ones received during commands.
+    pub async fn next_event(&mut self) -> Result<QmpEvent, DisplayError> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            let message = self.read_message().await?;
+            self.queue_event(message);
+        }
+    }
+
+    fn request(&mut self, command: &str, arguments: Option<Value>) -> (u64, Vec<u8>) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut request = json!({ "execute": command, "id": id });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        let mut request = request.to_string().into_bytes();
+        request.push(b'\n');
+        (id, request)
+    }
+
+    async fn response(&mut self, id: u64) -> Result<Value, DisplayError> {
+        loop {
+            let mut message = self.read_message().await?;
+            if message.get("event").is_some() {
+                self.queue_event(message);
+                continue;
+            }
+            if message.get("id") != Some(&json!(id)) {
+                debug!("Ignoring QMP message for another command: {}", message);
+                continue;
+            }
+
+            if let Some(error) = message.get("error") {
+                let class = error.get("class").and_then(Value::as_str).unwrap_or("GenericError");
+                let desc = error.get("desc").and_then(Value::as_str).unwrap_or("");
+                return Err(DisplayError::Protocol(format!("QMP error {}: {}", class, desc)));
+            }
+            return match message.get_mut("return") {
+                Some(value) => Ok(value.take()),
+                None => Err(DisplayError::Protocol(format!("Unexpected QMP response: {}", message))),
+            };
+        }
+    }
+
+    fn queue_event(&mut self, message: Value) {
+        let Value::Object(mut message) = message else {
+            return;
+        };
+        let Some(Value::String(event)) = message.remove("event") else {
+            return;
+        };
+        self.events.push_back(QmpEvent {
+            event,
+            data: message.remove("data").unwrap_or(Value::Null),
+            timestamp: message.remove("timestamp").unwrap_or(Value::Null),
+        });
+    }
+
+    /// Reads one JSON message, they are separated by newlines.
+    async fn read_message(&mut self) -> Result<Value, DisplayError> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                return Ok(serde_json::from_slice(&line)?);
+            }
+
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "QMP connection closed").into());
+            }
+        }
+    }
+}
+
+/// Name of the fd handed to QEMU for `add_client`.
+const DBUS_DISPLAY_FDNAME: &str = "dbus-display";
+
+/// Hands QEMU one end of a socket pair for `-display dbus,p2p=yes` and returns the other end,
+/// for a peer-to-peer D-Bus connection to the display.
+pub async fn add_dbus_display_client(path: impl AsRef<Path>) -> Result<std::os::unix::net::UnixStream, DisplayError> {
+    let mut qmp = QmpClient::connect(path).await?;
+    let (p0, p1) = std::os::unix::net::UnixStream::pair()?;
+
+    qmp.execute_with_fd("getfd", Some(json!({ "fdname": DBUS_DISPLAY_FDNAME })), p0.as_raw_fd()).await?;
+    qmp.execute(
+        "add_client",
+        Some(json!({ "protocol": "@dbus-display", "fdname": DBUS_DISPLAY_FDNAME })),
+    ).await?;
+
+    Ok(p1)
+}
+
+/// Sends `data` with `fd` attached as SCM_RIGHTS ancillary data.
+fn send_with_fd(socket: RawFd, data: &[u8], fd: RawFd) -> io::Result<usize> {
+    unsafe {
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let space = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as usize;
+        let mut control = vec![0u8; space];
+
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+
+        let sent = libc::sendmsg(socket, &msg, libc::MSG_NOSIGNAL);
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::{UnixListener, UnixStream as StdUnixStream};
+    use std::path::PathBuf;
+    use std::thread::JoinHandle;
+
+    /// Receives one line, and the fd passed with it if any.
+    fn recv_line(stream: &mut StdUnixStream) -> (Value, Option<RawFd>) {
+        let mut line = Vec::new();
+        let mut fd = None;
+        loop {
+            let mut byte = 0u8;
+            let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+            let mut control = vec![0u8; space];
+            let mut iov = libc::iovec {
+                iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+                iov_len: 1,
+            };
+            let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = space as _;
+
+            let received = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) };
+            assert_eq!(received, 1, "client hung up");
+            let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+            if !cmsg.is_null() {
+                unsafe {
+                    assert_eq!((*cmsg).cmsg_type, libc::SCM_RIGHTS);
+                    fd = Some(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd));
+                }
+            }
+
+            if byte == b'\n' {
+                return (serde_json::from_slice(&line).unwrap(), fd);
+            }
+            line.push(byte);
+        }
+    }
+
+    fn send(stream: &mut StdUnixStream, message: Value) {
+        stream.write_all(format!("{}\r\n", message).as_bytes()).unwrap();
+    }
+
+    /// Scripted QMP server, `script` runs after the greeting and capabilities negotiation.
+    fn qmp_server(name: &str, script: impl FnOnce(&mut StdUnixStream) + Send + 'static) -> (PathBuf, JoinHandle<()>) {
+        let path = std::env::temp_dir().join(format!("qmp-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            send(&mut stream, json!({
+                "QMP": { "version": { "qemu": { "major": 8, "minor": 2, "micro": 0 } }, "capabilities": ["oob"] }
+            }));
+
+            let (request, _) = recv_line(&mut stream);
+            assert_eq!(request["execute"], "qmp_capabilities");
+            send(&mut stream, json!({ "return": {}, "id": request["id"] }));
+
+            script(&mut stream);
+        });
+
+        (path, server)
+    }
+
+    #[tokio::test]
+    async fn negotiates_and_runs_commands() {
+        let (path, server) = qmp_server("commands", |stream| {
+            let (request, _) = recv_line(stream);
+            assert_eq!(request["execute"], "query-status");
+            send(stream, json!({ "event": "RESUME", "data": {}, "timestamp": { "seconds": 1, "microseconds": 2 } }));
+            send(stream, json!({ "return": { "running": true, "status": "running" }, "id": request["id"] }));
+
+            let (request, _) = recv_line(stream);
+            assert_eq!(request["arguments"]["device"], "nope");
+            send(stream, json!({ "error": { "class": "DeviceNotFound", "desc": "Device 'nope' not found" }, "id": request["id"] }));
+
+            send(stream, json!({ "event": "SHUTDOWN", "data": { "guest": true } }));
+        });
+
+        let mut qmp = QmpClient::connect(&path).await.unwrap();
+        assert_eq!(qmp.greeting["version"]["qemu"]["major"], 8);
+
+        let status = qmp.execute("query-status", None).await.unwrap();
+        assert_eq!(status["status"], "running");
+
+        let err = qmp.execute("device_del", Some(json!({ "device": "nope" }))).await.unwrap_err();
+        assert!(err.to_string().contains("DeviceNotFound"));
+
+        // The event that arrived during a command comes first
+        assert_eq!(qmp.next_event().await.unwrap().event, "RESUME");
+        let shutdown = qmp.next_event().await.unwrap();
+        assert_eq!(shutdown.event, "SHUTDOWN");
+        assert_eq!(shutdown.data["guest"], true);
+
+        server.join().unwrap();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn hands_a_socket_to_add_client() {
+        let (path, server) = qmp_server("add-client", |stream| {
+            let (request, fd) = recv_line(stream);
+            assert_eq!(request["execute"], "getfd");
+            assert_eq!(request["arguments"]["fdname"], DBUS_DISPLAY_FDNAME);
+            let mut display = unsafe { StdUnixStream::from_raw_fd(fd.expect("no fd passed with getfd")) };
+            send(stream, json!({ "return": {}, "id": request["id"] }));
+
+            let (request, fd) = recv_line(stream);
+            assert_eq!(fd, None);
+            assert_eq!(request["execute"], "add_client");
+            assert_eq!(request["arguments"]["protocol"], "@dbus-display");
+            assert_eq!(request["arguments"]["fdname"], DBUS_DISPLAY_FDNAME);
+            send(stream, json!({ "return": {}, "id": request["id"] }));
+
+            display.write_all(b"hello").unwrap();
+        });
+
+        let display = add_dbus_display_client(&path).await.unwrap();
+        server.join().unwrap();
+
+        let mut hello = String::new();
+        BufReader::new(display).take(5).read_to_string(&mut hello).unwrap();
+        assert_eq!(hello, "hello");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn rejects_other_protocols() {
+        let path = std::env::temp_dir().join(format!("qmp-greeting-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            send(&mut stream, json!({ "hello": "world" }));
+            // Wait for the client to hang up
+            let _ = BufReader::new(stream).fill_buf().map(|buf| buf.len());
+        });
+
+        assert!(QmpClient::connect(&path).await.is_err());
+        server.join().unwrap();
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,54 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use libc::{MAP_FAILED, MAP_SHARED, mmap, munmap, PROT_READ};
+use crate::display::error::DisplayError;
+
+/// Read-only shared mapping of a file descriptor, unmapped on drop.
+///
+/// The mapping stays valid after the descriptor is closed, so it can outlive the message that
+/// carried the fd.
+#[derive(Debug)]
+pub struct SharedMap {
+    ptr: *mut libc::c_void,
+    size: usize,
+}
+
+// The mapping is read-only and owned, so it can move between the listener tasks.
+unsafe impl Send for SharedMap {}
+unsafe impl Sync for SharedMap {}
+
+impl SharedMap {
+    pub fn new(fd: RawFd, size: usize) -> Result<Self, DisplayError> {
+        if size == 0 {
+            return Err(DisplayError::Mmap(io::Error::new(io::ErrorKind::InvalidInput, "Empty mapping")));
+        }
+
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                size,
+                PROT_READ,
+                MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if ptr == MAP_FAILED {
+            return Err(DisplayError::Mmap(io::Error::last_os_error()));
+        }
+
+        Ok(Self { ptr, size })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.size) }
+    }
+}
+
+impl Drop for SharedMap {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr, self.size);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::Arc;
+use std::time::Duration;
+use futures_util::StreamExt;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{watch, Mutex};
+use zbus::Connection;
+use tracing::{info, warn};
+use crate::display::connect::ConnectOptions;
+use crate::display::console::Console;
+use crate::display::console_listenner::ConsoleListenerHandler;
+use crate::display::utils::WindowCommand;
+use crate::display::vm::Vm;
+use crate::display::error::DisplayError;
+
+const FIRST_RETRY: Duration = Duration::from_millis(500);
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+/// State of the link to QEMU, sent to the window as `WindowCommand::Connection`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+    /// Waiting `delay` before the attempt number `attempt`
+    Reconnecting { attempt: u32, delay: Duration },
+}
+
+/// Keeps a graphical console connected, attaching again whenever QEMU restarts or the
+/// listener goes away.
+#[derive(Debug)]
+pub struct ConsoleSupervisor {
+    options: ConnectOptions,
+    service: String,
+    console: watch::Sender<Option<Arc<Console>>>,
+}
+
+impl ConsoleSupervisor {
+    pub fn new(options: ConnectOptions, service: &str) -> Self {
+        let (console, _) = watch::channel(None);
+
+        Self {
+            options,
+            service: service.to_string(),
+            console,
+        }
+    }
+
+    /// The console while connected, `None` in between.
+    pub fn console(&self) -> watch::Receiver<Option<Arc<Console>>> {
+        self.console.subscribe()
+    }
+
+    /// Connects and registers `handler`, then does it again after every disconnection, with
+    /// an exponential backoff between failed attempts. Returns once `states` is closed, or
+    /// when the connection can't be made again, as with `ConnectOptions::Fd`.
+    pub async fn run<H: ConsoleListenerHandler>(self, handler: H, states: Sender<WindowCommand>) {
+        let handler = Arc::new(Mutex::new(handler));
+        let mut attempt = 0;
+
+        loop {
+            match self.attach(&handler).await {
+                Ok((connection, console)) => {
+                    attempt = 0;
+                    self.console.send_replace(Some(Arc::clone(&console)));
+                    if states.send(WindowCommand::Connection(ConnectionState::Connected)).await.is_err() {
+                        return;
+                    }
+
+                    self.disconnection(&connection, &console).await;
+                    console.unregister_listener().await;
+                    self.console.send_replace(None);
+                    if states.send(WindowCommand::Connection(ConnectionState::Disconnected)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => warn!("Failed to attach to the console: {}", e),
+            }
+
+            if !self.options.reconnects() {
+                info!("The connection can't be made again, giving up");
+                return;
+            }
+
+            attempt += 1;
+            let delay = backoff(attempt);
+            let state = ConnectionState::Reconnecting { attempt, delay };
+            if states.send(WindowCommand::Connection(state)).await.is_err() {
+                return;
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn attach<H: ConsoleListenerHandler>(
+        &self,
+        handler: &Arc<Mutex<H>>,
+    ) -> Result<(Connection, Arc<Console>), DisplayError> {
+        let connection = self.options.try_clone()?.connect().await?;
+        let vm = Vm::with_service(&connection, &self.service).await?;
+        let console = vm.graphic_console().await?;
+        console.register_shared_listener(Arc::clone(handler)).await?;
+
+        Ok((connection, Arc::new(console)))
+    }
+
+    /// Resolves when the listener connection closes or, on a bus, the service changes owner.
+    async fn disconnection(&self, connection: &Connection, console: &Console) {
+        let owner_changed = async {
+            if connection.is_bus() {
+                if let Ok(dbus) = zbus::fdo::DBusProxy::new(connection).await {
+                    if let Ok(mut changes) = dbus
+                        .receive_name_owner_changed_with_args(&[(0, self.service.as_str())])
+                        .await
+                    {
+                        changes.next().await;
+                        return;
+                    }
+                }
+            }
+            std::future::pending::<()>().await
+        };
+
+        tokio::select! {
+            _ = owner_changed => info!("{} changed owner", self.service),
+            _ = console.listener_closed() => info!("Listener disconnected"),
+        }
+    }
+}
+
+/// Delay before attempt number `attempt`, doubling from `FIRST_RETRY` up to `MAX_RETRY`.
+fn backoff(attempt: u32) -> Duration {
+    FIRST_RETRY
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(MAX_RETRY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        assert_eq!(backoff(1), FIRST_RETRY);
+        assert_eq!(backoff(2), FIRST_RETRY * 2);
+        assert_eq!(backoff(4), FIRST_RETRY * 8);
+        assert_eq!(backoff(7), MAX_RETRY);
+        assert_eq!(backoff(u32::MAX), MAX_RETRY);
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use zbus::dbus_proxy;
+use zvariant::Type;
+use crate::display::error::DisplayError;
+
+#[repr(u32)]
+#[derive(Deserialize_repr, Serialize_repr, Type, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TouchEventKind {
+    Begin,
+    Update,
+    End,
+    Cancel,
+}
+
+#[dbus_proxy(default_service = "org.qemu", interface = "org.qemu.Display1.MultiTouch")]
+pub trait MultiTouch {
+    /// SendEvent method, `x` and `y` are in guest pixels
+    fn send_event(&self, kind: TouchEventKind, num_slot: u64, x: f64, y: f64) -> zbus::Result<()>;
+
+    #[dbus_proxy(property)]
+    fn max_slots(&self) -> zbus::Result<i32>;
+}
+
+/// Assigns the guest slots to host touch points, at most `MaxSlots` at a time.
+#[derive(Debug, Default)]
+pub struct TouchSlots {
+    max_slots: u64,
+    /// Slot of each host touch id
+    slots: HashMap<u64, u64>,
+}
+
+impl TouchSlots {
+    pub fn new(max_slots: i32) -> Self {
+        Self {
+            max_slots: max_slots.max(0) as u64,
+            slots: HashMap::new(),
+        }
+    }
+
+    /// Slot to send an event of touch point `id` on, `None` to drop it, e.g. when all the
+    /// slots are taken when it begins.
+    pub fn slot(&mut self, kind: TouchEventKind, id: u64) -> Option<u64> {
+        match kind {
+            TouchEventKind::Begin => {
+                if let Some(slot) = self.slots.get(&id) {
+                    return Some(*slot);
+                }
+                let slot = (0..self.max_slots).find(|slot| !self.slots.values().any(|s| s == slot))?;
+                self.slots.insert(id, slot);
+                Some(slot)
+            }
+            TouchEventKind::Update => self.slots.get(&id).copied(),
+            TouchEventKind::End | TouchEventKind::Cancel => self.slots.remove(&id),
+        }
+    }
+}
+
+/// One step of a synthetic gesture.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TouchEvent {
+    pub kind: TouchEventKind,
+    pub slot: u64,
+    pub x: f64,
+    pub y: f64,
+}
+
+impl TouchEvent {
+    fn new(kind: TouchEventKind, slot: u64, (x, y): (f64, f64)) -> Self {
+        Self { kind, slot, x, y }
+    }
+}
+
+/// A finger down and up at (`x`, `y`).
+pub fn tap(x: f64, y: f64) -> Vec<Vec<TouchEvent>> {
+    vec![
+        vec![TouchEvent::new(TouchEventKind::Begin, 0, (x, y))],
+        vec![TouchEvent::new(TouchEventKind::End, 0, (x, y))],
+    ]
+}
+
+/// A finger dragged from `from` to `to` in `steps` moves.
+pub fn swipe(from: (f64, f64), to: (f64, f64), steps: u32) -> Vec<Vec<TouchEvent>> {
+    let steps = steps.max(1);
+    let mut frames = vec![vec![TouchEvent::new(TouchEventKind::Begin, 0, from)]];
+    for step in 1..=steps {
+        let t = step as f64 / steps as f64;
+        frames.push(vec![TouchEvent::new(TouchEventKind::Update, 0, lerp(from, to, t))]);
+    }
+    frames.push(vec![TouchEvent::new(TouchEventKind::End, 0, to)]);
+    frames
+}
+
+/// Two fingers on a horizontal line through `center`, moving from `from_distance` to
+/// `to_distance` apart in `steps` moves. Pinches in when the distance shrinks, out otherwise.
+pub fn pinch(center: (f64, f64), from_distance: f64, to_distance: f64, steps: u32) -> Vec<Vec<TouchEvent>> {
+    let fingers = |distance: f64| {
+        [
+            (center.0 - distance / 2.0, center.1),
+            (center.0 + distance / 2.0, center.1),
+        ]
+    };
+    let both = |kind: TouchEventKind, distance: f64| {
+        let [left, right] = fingers(distance);
+        vec![TouchEvent::new(kind, 0, left), TouchEvent::new(kind, 1, right)]
+    };
+
+    let steps = steps.max(1);
+    let mut frames = vec![both(TouchEventKind::Begin, from_distance)];
+    for step in 1..=steps {
+        let t = step as f64 / steps as f64;
+        frames.push(both(TouchEventKind::Update, from_distance + (to_distance - from_distance) * t));
+    }
+    frames.push(both(TouchEventKind::End, to_distance));
+    frames
+}
+
+fn lerp(from: (f64, f64), to: (f64, f64), t: f64) -> (f64, f64) {
+    (from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t)
+}
+
+impl<'a> MultiTouchProxy<'a> {
+    /// Plays a gesture from `tap`, `swipe` or `pinch`, one frame of events every `interval`.
+    pub async fn play(&self, frames: &[Vec<TouchEvent>], interval: Duration) -> Result<(), DisplayError> {
+        let max_slots = self.max_slots().await?;
+        if let Some(event) = frames.iter().flatten().find(|event| event.slot >= max_slots.max(0) as u64) {
+            return Err(DisplayError::Protocol(format!("Touch slot {} is over the {} slots of the guest", event.slot, max_slots)));
+        }
+
+        for (i, frame) in frames.iter().enumerate() {
+            if i > 0 {
+                tokio::time::sleep(interval).await;
+            }
+            for event in frame {
+                self.send_event(event.kind, event.slot, event.x, event.y).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TouchEventKind::*;
+
+    #[test]
+    fn slots_are_reused_once_released() {
+        let mut slots = TouchSlots::new(2);
+        assert_eq!(slots.slot(Begin, 10), Some(0));
+        assert_eq!(slots.slot(Begin, 11), Some(1));
+        // No slot left for a third finger, nor its moves
+        assert_eq!(slots.slot(Begin, 12), None);
+        assert_eq!(slots.slot(Update, 12), None);
+
+        assert_eq!(slots.slot(Update, 11), Some(1));
+        assert_eq!(slots.slot(End, 10), Some(0));
+        assert_eq!(slots.slot(Update, 10), None);
+        assert_eq!(slots.slot(Begin, 12), Some(0));
+        assert_eq!(slots.slot(Cancel, 11), Some(1));
+    }
+
+    #[test]
+    fn no_slots_without_multi_touch() {
+        let mut slots = TouchSlots::new(0);
+        assert_eq!(slots.slot(Begin, 1), None);
+        assert_eq!(TouchSlots::new(-1).slot(Begin, 1), None);
+    }
+
+    #[test]
+    fn tap_is_down_and_up() {
+        assert_eq!(
+            tap(4.0, 2.0),
+            [[TouchEvent::new(Begin, 0, (4.0, 2.0))], [TouchEvent::new(End, 0, (4.0, 2.0))]]
+        );
+    }
+
+    #[test]
+    fn swipe_moves_in_even_steps() {
+        let frames = swipe((0.0, 0.0), (10.0, 20.0), 2);
+        assert_eq!(
+            frames,
+            [
+                [TouchEvent::new(Begin, 0, (0.0, 0.0))],
+                [TouchEvent::new(Update, 0, (5.0, 10.0))],
+                [TouchEvent::new(Update, 0, (10.0, 20.0))],
+                [TouchEvent::new(End, 0, (10.0, 20.0))],
+            ]
+        );
+    }
+
+    #[test]
+    fn pinch_moves_both_fingers() {
+        let frames = pinch((50.0, 10.0), 40.0, 20.0, 1);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0], [TouchEvent::new(Begin, 0, (30.0, 10.0)), TouchEvent::new(Begin, 1, (70.0, 10.0))]);
+        assert_eq!(frames[1], [TouchEvent::new(Update, 0, (40.0, 10.0)), TouchEvent::new(Update, 1, (60.0, 10.0))]);
+        assert_eq!(frames[2], [TouchEvent::new(End, 0, (40.0, 10.0)), TouchEvent::new(End, 1, (60.0, 10.0))]);
+    }
+}
@@ -1,16 +1,43 @@
 #[cfg(unix)]
 use std::os::unix::{io::AsRawFd, net::UnixStream};
-use minifb::Key;
 use zbus::zvariant::Fd;
+use crate::display::cursor::CursorMode;
+use crate::display::framebuffer::Rect;
+#[cfg(feature = "pixels")]
+use crate::display::keymap::Hotkey;
+use crate::display::mouse::MouseButton;
+use crate::display::supervisor::ConnectionState;
+use crate::display::touch::TouchEventKind;
+use crate::display::error::DisplayError;
 
 
-pub fn prepare_uds_pass(us: &UnixStream) -> Result<Fd, Box<dyn std::error::Error + Send + Sync>> {
+pub fn prepare_uds_pass(us: &UnixStream) -> Result<Fd, DisplayError> {
     Ok(us.as_raw_fd().into())
 }
 
 pub enum WindowCommand {
-    Update(Vec<u32>, usize, usize),
-    UpdateDMABUF(Vec<u8>),
-    MouseMove(f32, f32), // x, y
-    KeyPress(Key),
-}
\ No newline at end of file
+    Resize(usize, usize), // width, height
+    Damage(Rect),
+    Cursor(Vec<Rect>), // areas the cursor left and now covers
+    MouseMove(f32, f32), // x, y in guest coordinates
+    MouseRelMove(f64, f64), // dx, dy
+    SetAbsolute(bool), // the guest mouse switched between absolute and relative
+    UiInfo(u16, u16, u32, u32), // width_mm, height_mm, width, height
+    MousePress(MouseButton),
+    MouseRelease(MouseButton),
+    KeyPress(u32), // qnum
+    KeyRelease(u32),
+    Touch(TouchEventKind, u64, f64, f64), // kind, host touch id, x, y in guest coordinates
+    Connection(ConnectionState),
+}
+
+/// Viewer settings shared by the window backends.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ViewerOptions {
+    pub cursor_mode: CursorMode,
+    /// Releases the pointer grab of the relative mouse mode.
+    #[cfg(feature = "pixels")]
+    pub release_hotkey: Hotkey,
+    /// Asks the guest to change its resolution to the window size.
+    pub auto_resize: bool,
+}
@@ -0,0 +1,381 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use futures_util::StreamExt;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+use tracing::{error, warn};
+use crate::display::console::Console;
+use crate::display::console_handler::DisplayHandlers;
+use crate::display::cursor::CursorState;
+use crate::display::framebuffer::{Framebuffer, Rect};
+use crate::display::supervisor::{ConnectionState, ConsoleSupervisor};
+use crate::display::touch::TouchSlots;
+use crate::display::utils::{ViewerOptions, WindowCommand};
+
+/// Window side of the viewer, told what changed in the guest display.
+///
+/// The framebuffer and cursor are already up to date when a method is called, the sink only
+/// has to present them.
+pub trait DisplaySink {
+    /// The guest resolution changed, everything has to be redrawn.
+    fn on_resize(&mut self, width: u32, height: u32);
+
+    /// A region of the framebuffer changed.
+    fn on_damage(&mut self, rect: Rect);
+
+    /// The cursor moved or changed shape, `rects` being the areas it left and now covers.
+    fn on_cursor(&mut self, rects: Vec<Rect>);
+
+    /// The link to QEMU changed state.
+    fn on_state(&mut self, state: ConnectionState);
+
+    /// The guest mouse switched between absolute and relative.
+    fn on_absolute(&mut self, _absolute: bool) {}
+}
+
+/// Window side of the viewer producing the input of the user.
+pub trait InputSource {
+    /// Sends the input gathered since the last call to `input`, to be forwarded to the guest.
+    fn poll_input(&mut self, input: &UnboundedSender<WindowCommand>);
+}
+
+/// Everything a window backend gets from the viewer: the surfaces to present, the commands for
+/// its `DisplaySink` and where its `InputSource` sends to.
+///
+/// The input channel is unbounded: a window can't wait for the guest, and dropping input
+/// could lose a release and leave a key or button stuck.
+pub struct Viewer {
+    pub framebuffer: Arc<Mutex<Framebuffer>>,
+    pub cursor: Arc<Mutex<CursorState>>,
+    pub commands: Receiver<WindowCommand>,
+    pub input: UnboundedSender<WindowCommand>,
+}
+
+impl Viewer {
+    /// Connects through `supervisor`, forwarding the input to whichever console is connected
+    /// and following the mouse mode of the guest.
+    pub fn start(supervisor: ConsoleSupervisor) -> Self {
+        let (sender, commands) = mpsc::channel(100);
+        let (input, input_receiver) = mpsc::unbounded_channel();
+
+        let handlers = DisplayHandlers::new(sender.clone());
+        let framebuffer = handlers.framebuffer();
+        let cursor = handlers.cursor();
+        let consoles = supervisor.console();
+        tokio::spawn(supervisor.run(handlers, sender.clone()));
+        tokio::spawn(forward_input(input_receiver, consoles.clone()));
+        #[cfg(feature = "host-clipboard")]
+        tokio::spawn(share_clipboard(consoles.clone()));
+        tokio::spawn(follow_absolute(consoles, sender));
+
+        Self {
+            framebuffer,
+            cursor,
+            commands,
+            input,
+        }
+    }
+}
+
+/// Hands the commands received so far to `sink`.
+pub fn dispatch_commands(commands: &mut Receiver<WindowCommand>, sink: &mut impl DisplaySink) {
+    while let Ok(command) = commands.try_recv() {
+        match command {
+            WindowCommand::Resize(width, height) => sink.on_resize(width as u32, height as u32),
+            WindowCommand::Damage(rect) => sink.on_damage(rect),
+            WindowCommand::Cursor(rects) => sink.on_cursor(rects),
+            WindowCommand::Connection(state) => sink.on_state(state),
+            WindowCommand::SetAbsolute(absolute) => sink.on_absolute(absolute),
+            // Input goes the other way
+            _ => {}
+        }
+    }
+}
+
+/// Window backend, picked with `--backend`. Only the ones in `Backend::AVAILABLE` are built.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backend {
+    Pixels,
+    Minifb,
+}
+
+impl Backend {
+    /// Backends built in, the first one being the default.
+    pub const AVAILABLE: &'static [Backend] = &[
+        #[cfg(feature = "pixels")]
+        Backend::Pixels,
+        #[cfg(feature = "minifb")]
+        Backend::Minifb,
+    ];
+
+    /// Opens the window and runs the viewer until it is closed.
+    pub async fn run(self, supervisor: ConsoleSupervisor, options: ViewerOptions) {
+        if !Backend::AVAILABLE.contains(&self) {
+            error!("Built without the {} backend", self);
+            return;
+        }
+        let viewer = Viewer::start(supervisor);
+
+        match self {
+            #[cfg(feature = "pixels")]
+            Backend::Pixels => crate::display::pixels_window::build_pixels_window(viewer, options).await,
+            #[cfg(feature = "minifb")]
+            Backend::Minifb => crate::display::minifb_window::build_minifb_window(viewer, options).await,
+            #[allow(unreachable_patterns)]
+            _ => drop((viewer, options)),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            Backend::Pixels => "pixels",
+            Backend::Minifb => "minifb",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Parses the name of a built-in backend, `pixels` or `minifb`.
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let backend = match s {
+            "pixels" => Backend::Pixels,
+            "minifb" => Backend::Minifb,
+            _ => return Err(format!("Unknown backend: {}", s)),
+        };
+        if !Backend::AVAILABLE.contains(&backend) {
+            return Err(format!("Built without the {} backend, enable the {} feature", s, s));
+        }
+
+        Ok(backend)
+    }
+}
+
+/// Sends the input of the window to the connected console, dropping it while disconnected.
+async fn forward_input(
+    mut input: UnboundedReceiver<WindowCommand>,
+    consoles: watch::Receiver<Option<Arc<Console>>>,
+) {
+    // Slots are assigned once MaxSlots is known, on the first touch on each console
+    let mut touch_slots: Option<TouchSlots> = None;
+    let mut touch_console: Option<Arc<Console>> = None;
+    let mut rel_motion = RelMotion::default();
+    while let Some(command) = input.recv().await {
+        let Some(console) = consoles.borrow().clone() else {
+            continue;
+        };
+        if !touch_console.as_ref().is_some_and(|touched| Arc::ptr_eq(touched, &console)) {
+            touch_console = Some(Arc::clone(&console));
+            touch_slots = None;
+        }
+        let result = match command {
+            WindowCommand::KeyPress(qnum) => console.keyboard.press(qnum).await,
+            WindowCommand::KeyRelease(qnum) => console.keyboard.release(qnum).await,
+            WindowCommand::MouseMove(x, y) => console.mouse.set_abs_position(x as u32, y as u32).await,
+            WindowCommand::MouseRelMove(dx, dy) => match rel_motion.take(dx, dy) {
+                (0, 0) => Ok(()),
+                (dx, dy) => console.mouse.rel_motion(dx, dy).await,
+            },
+            WindowCommand::MousePress(button) => console.mouse.press(button).await,
+            WindowCommand::MouseRelease(button) => console.mouse.release(button).await,
+            WindowCommand::Touch(kind, id, x, y) => {
+                let slots = match touch_slots.as_mut() {
+                    Some(slots) => slots,
+                    None => match console.multi_touch.max_slots().await {
+                        Ok(max_slots) => touch_slots.insert(TouchSlots::new(max_slots)),
+                        // Asked again on the next touch
+                        Err(e) => {
+                            warn!("Failed to get the touch slots: {}", e);
+                            continue;
+                        }
+                    },
+                };
+                match slots.slot(kind, id) {
+                    Some(slot) => console.multi_touch.send_event(kind, slot, x, y).await,
+                    None => Ok(()),
+                }
+            }
+            WindowCommand::UiInfo(width_mm, height_mm, width, height) => {
+                console.proxy.set_ui_info(width_mm, height_mm, 0, 0, width, height).await
+            }
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to forward input: {}", e);
+        }
+    }
+}
+
+/// How often the desktop clipboard is checked for changes to offer to the guest.
+#[cfg(feature = "host-clipboard")]
+const CLIPBOARD_POLL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Shares the desktop clipboard with every console the supervisor connects to.
+#[cfg(feature = "host-clipboard")]
+async fn share_clipboard(mut consoles: watch::Receiver<Option<Arc<Console>>>) {
+    loop {
+        let console = consoles.borrow_and_update().clone();
+        if let Some(console) = console {
+            tokio::select! {
+                result = share_clipboard_with(&console) => {
+                    if let Err(e) = result {
+                        warn!("Clipboard not shared: {}", e);
+                    }
+                }
+                changed = consoles.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            }
+        }
+        if consoles.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Registers the desktop clipboard with the QEMU of `console`, then offers it to the guest
+/// whenever something is copied on the desktop.
+#[cfg(feature = "host-clipboard")]
+async fn share_clipboard_with(console: &Console) -> Result<(), crate::display::error::DisplayError> {
+    use crate::display::clipboard::ArboardClipboard;
+    use crate::display::vm::Vm;
+
+    let proxy = console.proxy.inner();
+    let vm = Vm::with_service(proxy.connection(), proxy.destination().as_str()).await?;
+    let clipboard = vm.clipboard(ArboardClipboard::new()?, false).await?;
+
+    let mut poll = tokio::time::interval(CLIPBOARD_POLL);
+    loop {
+        poll.tick().await;
+        if clipboard.with_host(ArboardClipboard::changed) {
+            clipboard.host_changed().await?;
+        }
+    }
+}
+
+/// Relative motion in whole pixels, carrying the fraction the guest can't take over to the
+/// next motion so that slow movements aren't lost.
+#[derive(Debug, Default)]
+struct RelMotion {
+    x: f64,
+    y: f64,
+}
+
+impl RelMotion {
+    fn take(&mut self, dx: f64, dy: f64) -> (i32, i32) {
+        self.x += dx;
+        self.y += dy;
+        let (x, y) = (self.x.trunc(), self.y.trunc());
+        self.x -= x;
+        self.y -= y;
+        (x as i32, y as i32)
+    }
+}
+
+/// Sends `SetAbsolute` for the mouse mode of every console the supervisor connects to.
+async fn follow_absolute(
+    mut consoles: watch::Receiver<Option<Arc<Console>>>,
+    sender: Sender<WindowCommand>,
+) {
+    loop {
+        let console = consoles.borrow_and_update().clone();
+        if let Some(console) = console {
+            tokio::select! {
+                _ = follow_is_absolute(&console, &sender) => {}
+                changed = consoles.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            }
+        }
+        if consoles.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Sends `SetAbsolute` with the current `IsAbsolute` of the console and every change to it.
+async fn follow_is_absolute(console: &Console, sender: &Sender<WindowCommand>) {
+    if let Ok(absolute) = console.mouse.is_absolute().await {
+        let _ = sender.send(WindowCommand::SetAbsolute(absolute)).await;
+    }
+    let mut changes = console.mouse.receive_is_absolute_changed().await;
+    while let Some(change) = changes.next().await {
+        if let Ok(absolute) = change.get().await {
+            let _ = sender.send(WindowCommand::SetAbsolute(absolute)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingSink {
+        calls: Vec<String>,
+    }
+
+    impl DisplaySink for RecordingSink {
+        fn on_resize(&mut self, width: u32, height: u32) {
+            self.calls.push(format!("resize {}x{}", width, height));
+        }
+
+        fn on_damage(&mut self, rect: Rect) {
+            self.calls.push(format!("damage {}x{}", rect.width, rect.height));
+        }
+
+        fn on_cursor(&mut self, rects: Vec<Rect>) {
+            self.calls.push(format!("cursor {}", rects.len()));
+        }
+
+        fn on_state(&mut self, state: ConnectionState) {
+            self.calls.push(format!("state {:?}", state));
+        }
+    }
+
+    #[test]
+    fn commands_reach_the_sink_in_order() {
+        let (sender, mut commands) = mpsc::channel(10);
+        sender.try_send(WindowCommand::Resize(640, 480)).unwrap();
+        sender.try_send(WindowCommand::Damage(Rect { x: 0, y: 0, width: 8, height: 4 })).unwrap();
+        // Input and the optional notifications are skipped
+        sender.try_send(WindowCommand::KeyPress(0x1e)).unwrap();
+        sender.try_send(WindowCommand::SetAbsolute(false)).unwrap();
+        sender.try_send(WindowCommand::Cursor(Vec::new())).unwrap();
+        sender.try_send(WindowCommand::Connection(ConnectionState::Connected)).unwrap();
+
+        let mut sink = RecordingSink::default();
+        dispatch_commands(&mut commands, &mut sink);
+
+        assert_eq!(sink.calls, ["resize 640x480", "damage 8x4", "cursor 0", "state Connected"]);
+    }
+
+    #[test]
+    fn rel_motion_carries_fractions() {
+        let mut motion = RelMotion::default();
+        assert_eq!(motion.take(0.375, -0.375), (0, 0));
+        assert_eq!(motion.take(0.375, -0.375), (0, 0));
+        assert_eq!(motion.take(0.375, -0.375), (1, -1));
+        assert_eq!(motion.take(2.5, 0.0), (2, 0));
+        assert_eq!(motion.take(0.375, 0.0), (1, 0));
+    }
+
+    #[test]
+    fn backend_names() {
+        for backend in Backend::AVAILABLE {
+            assert_eq!(backend.to_string().parse::<Backend>(), Ok(*backend));
+        }
+        assert!("gtk".parse::<Backend>().is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::Duration;
+use zbus::{dbus_proxy, CacheProperties, Connection};
+use crate::display::audio::Audio;
+use crate::display::chardev::Chardev;
+use crate::display::clipboard::{HostClipboard, SharedClipboard};
+use crate::display::connect::ConnectOptions;
+use crate::display::console::Console;
+use crate::display::error::DisplayError;
+
+/// Bus name QEMU asks for by default.
+pub const DEFAULT_SERVICE: &str = "org.qemu";
+
+#[dbus_proxy(
+    default_service = "org.qemu",
+    default_path = "/org/qemu/Display1/VM",
+    interface = "org.qemu.Display1.VM"
+)]
+pub trait Vm {
+    #[dbus_proxy(property)]
+    fn name(&self) -> zbus::Result<String>;
+
+    #[dbus_proxy(property, name = "UUID")]
+    fn uuid(&self) -> zbus::Result<String>;
+
+    #[dbus_proxy(property, name = "ConsoleIDs")]
+    fn console_ids(&self) -> zbus::Result<Vec<u32>>;
+}
+
+/// Value of the `Type` property of a console.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConsoleType {
+    Graphic,
+    Text,
+}
+
+impl FromStr for ConsoleType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Graphic" => Ok(ConsoleType::Graphic),
+            "Text" => Ok(ConsoleType::Text),
+            _ => Err(format!("Unknown console type: {}", s)),
+        }
+    }
+}
+
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct Vm {
+    #[derivative(Debug = "ignore")]
+    pub proxy: VmProxy<'static>,
+    #[derivative(Debug = "ignore")]
+    connection: Connection,
+    service: String,
+}
+
+impl Vm {
+    pub async fn new() -> Result<Self, DisplayError> {
+        Self::connect(ConnectOptions::Session).await
+    }
+
+    pub async fn connect(options: ConnectOptions) -> Result<Self, DisplayError> {
+        let connection = options.connect().await?;
+        Self::with_connection(&connection).await
+    }
+
+    pub async fn with_connection(connection: &Connection) -> Result<Self, DisplayError> {
+        Self::with_service(connection, DEFAULT_SERVICE).await
+    }
+
+    /// Uses the VM owning `service` on the bus, see `discover` to find them.
+    pub async fn with_service(connection: &Connection, service: &str) -> Result<Self, DisplayError> {
+        let proxy = VmProxy::builder(connection)
+            .destination(service.to_string())?
+            .build()
+            .await?;
+
+        Ok(Self {
+            proxy,
+            connection: connection.clone(),
+            service: service.to_string(),
+        })
+    }
+
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    /// Returns the consoles of the VM matching the given type and head, in `ConsoleIDs` order.
+    /// Pass `None` to skip a filter.
+    pub async fn consoles(
+        &self,
+        type_: Option<ConsoleType>,
+        head: Option<u32>,
+    ) -> Result<Vec<Console>, DisplayError> {
+        let mut consoles = Vec::new();
+
+        for idx in self.proxy.console_ids().await? {
+            let console = Console::with_service(&self.connection, &self.service, idx).await?;
+
+            if let Some(type_) = type_ {
+                if console.proxy.type_().await?.parse::<ConsoleType>().ok() != Some(type_) {
+                    continue;
+                }
+            }
+            if let Some(head) = head {
+                if console.proxy.head().await? != head {
+                    continue;
+                }
+            }
+            consoles.push(console);
+        }
+
+        Ok(consoles)
+    }
+
+    /// Picks the graphical console to display, preferring head 0.
+    pub async fn graphic_console(&self) -> Result<Console, DisplayError> {
+        let mut consoles = self.consoles(Some(ConsoleType::Graphic), None).await?;
+        if consoles.is_empty() {
+            return Err(DisplayError::Protocol("No graphical console found".to_string()));
+        }
+
+        let mut index = 0;
+        for (i, console) in consoles.iter().enumerate() {
+            if console.proxy.head().await? == 0 {
+                index = i;
+                break;
+            }
+        }
+
+        Ok(consoles.swap_remove(index))
+    }
+
+    /// Shares `host` with the guest clipboard, PNG images included when `images` is set.
+    pub async fn clipboard<C: HostClipboard>(
+        &self,
+        host: C,
+        images: bool,
+    ) -> Result<SharedClipboard<C>, DisplayError> {
+        SharedClipboard::register(&self.connection, &self.service, host, images).await
+    }
+
+    pub async fn audio(&self) -> Result<Audio, DisplayError> {
+        Audio::new(&self.connection, &self.service).await
+    }
+
+    /// Opens the chardev with the given id, e.g. `serial0` or `monitor0`.
+    pub async fn chardev(&self, name: &str) -> Result<Chardev, DisplayError> {
+        Chardev::with_service(&self.connection, &self.service, name).await
+    }
+
+    /// Ids of the chardevs exported on D-Bus, found by introspecting `/org/qemu/Display1`.
+    pub async fn chardev_names(&self) -> Result<Vec<String>, DisplayError> {
+        let introspectable = zbus::fdo::IntrospectableProxy::builder(&self.connection)
+            .destination(self.service.as_str())?
+            .path("/org/qemu/Display1")?
+            .build()
+            .await?;
+        let node = zbus::xml::Node::from_reader(introspectable.introspect().await?.as_bytes())?;
+
+        Ok(node
+            .nodes()
+            .iter()
+            .filter_map(|node| node.name()?.strip_prefix("Chardev_"))
+            .map(String::from)
+            .collect())
+    }
+}
+
+/// A VM found on the bus by `discover`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmInfo {
+    /// Bus name to pass to `Vm::with_service`
+    pub service: String,
+    pub name: String,
+    pub uuid: String,
+}
+
+/// Lists the VMs exposing `/org/qemu/Display1/VM` on the bus of `connection`.
+///
+/// A VM owning a well-known name is listed under it rather than its unique name, the other
+/// ones, which couldn't get `org.qemu` because another VM has it, under their unique name.
+pub async fn discover(connection: &Connection) -> Result<Vec<VmInfo>, DisplayError> {
+    let dbus = zbus::fdo::DBusProxy::new(connection).await?;
+    let names = dbus.list_names().await?;
+
+    // Well-known names first, so that their owners can be skipped afterwards
+    let mut names: Vec<_> = names
+        .into_iter()
+        .map(|name| name.to_string())
+        .filter(|name| !name.starts_with("org.freedesktop.") && Some(name.as_str()) != connection.unique_name().map(|n| n.as_str()))
+        .collect();
+    names.sort_by_key(|name| name.starts_with(':'));
+
+    let mut owners = HashSet::new();
+    let mut vms = Vec::new();
+    for name in names {
+        if !name.starts_with(':') {
+            if let Ok(owner) = dbus.get_name_owner(name.as_str().try_into()?).await {
+                owners.insert(owner.to_string());
+            }
+        } else if owners.contains(&name) {
+            continue;
+        }
+
+        // Peers that aren't QEMU may be slow to tell, don't let them hold the others
+        let info = tokio::time::timeout(Duration::from_secs(1), vm_info(connection, &name)).await;
+        if let Ok(Ok(info)) = info {
+            vms.push(info);
+        }
+    }
+
+    Ok(vms)
+}
+
+async fn vm_info(connection: &Connection, service: &str) -> Result<VmInfo, DisplayError> {
+    let proxy = VmProxy::builder(connection)
+        .destination(service.to_string())?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+
+    Ok(VmInfo {
+        service: service.to_string(),
+        name: proxy.name().await?,
+        uuid: proxy.uuid().await?,
+    })
+}
@@ -0,0 +1,16 @@
+//! Client for the D-Bus display of QEMU (`-display dbus`).
+//!
+//! The protocol layer (consoles, listeners, input, clipboard, audio, chardevs) is always
+//! built. The window backends of the viewer sit behind the `pixels` and `minifb` features.
+
+pub mod display;
+
+pub use display::connect::{ConnectOptions, ConnectSpec};
+pub use display::console::Console;
+pub use display::console_events::{ConsoleEvent, ConsoleEvents, OverflowPolicy};
+pub use display::console_listenner::ConsoleListenerHandler;
+pub use display::error::DisplayError;
+pub use display::keyboard::KeyboardProxy;
+pub use display::mouse::MouseProxy;
+pub use display::supervisor::ConsoleSupervisor;
+pub use display::vm::Vm;
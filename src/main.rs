@@ -1,38 +1,138 @@
-mod display;
-
 use std::error::Error;
-use std::sync::Arc;
-use display::{
-    console::Console,
-    console_listenner::ConsoleListenerHandler,
-    utils::{ WindowCommand},
-    pixels_window::build_pixels_window
-};
-use tokio::sync::{ mpsc::{self, Sender, Receiver}};
-use std::os::unix::io::AsRawFd;
-use std::os::fd::FromRawFd;
+use std::os::unix::io::FromRawFd;
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+use vm_streaming::display::utils::ViewerOptions;
+use vm_streaming::display::viewer::Backend;
+use vm_streaming::display::vm::{discover, DEFAULT_SERVICE};
+use vm_streaming::{ConnectOptions, ConnectSpec, ConsoleSupervisor};
 
+/// Options of the command line.
+struct Args {
+    backend: Backend,
+    connect: ConnectSpec,
+    service: String,
+    /// Print the VMs on the bus instead of showing one
+    list: bool,
+    options: ViewerOptions,
+}
 
+/// Parses `[--backend <name>] [--connect <spec>] [--service <name>] [--list] [--auto-resize]
+/// [--cursor <mode>] [--release-hotkey <keys>]`, `None` when the usage was asked for.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+    let Some(mut backend) = Backend::AVAILABLE.first().copied() else {
+        return Err("Built without a window backend, enable the pixels or minifb feature".to_string());
+    };
+    let mut connect = ConnectSpec::Options(ConnectOptions::Session);
+    let mut service = DEFAULT_SERVICE.to_string();
+    let mut list = false;
+    let mut options = ViewerOptions::default();
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    // Create the channel to manage the communication with the console
-    let (sender, mut receiver): (Sender<WindowCommand>, Receiver<WindowCommand>) = mpsc::channel(100);
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(None);
+        } else if arg == "--list" {
+            list = true;
+        } else if arg == "--auto-resize" {
+            options.auto_resize = true;
+        } else if let Some(name) = option_value(&arg, "--backend", &mut args)? {
+            backend = name.parse()?;
+        } else if let Some(spec) = option_value(&arg, "--connect", &mut args)? {
+            connect = spec.parse()?;
+        } else if let Some(name) = option_value(&arg, "--service", &mut args)? {
+            service = name;
+        } else if let Some(mode) = option_value(&arg, "--cursor", &mut args)? {
+            options.cursor_mode = mode.parse()?;
+        } else if let Some(keys) = option_value(&arg, "--release-hotkey", &mut args)? {
+            #[cfg(feature = "pixels")]
+            {
+                options.release_hotkey = keys.parse()?;
+            }
+            #[cfg(not(feature = "pixels"))]
+            return Err(format!("--release-hotkey {} needs the pixels backend", keys));
+        } else {
+            return Err(format!("Unknown argument: {}", arg));
+        }
+    }
 
-    // Create the console
-    let console_handler = Arc::new(Console::new(0).await);
+    Ok(Some(Args { backend, connect, service, list, options }))
+}
 
-    // Using minifb
-    // build_minifb_window(sender, receiver, console_handler).await;
+/// Value of `arg` if it is the option `name`, given as `name <value>` or `name=<value>`.
+fn option_value(arg: &str, name: &str, args: &mut impl Iterator<Item = String>) -> Result<Option<String>, String> {
+    if arg == name {
+        return args.next().map(Some).ok_or_else(|| format!("{} needs a value", name));
+    }
 
-    // Using Pixels
-    build_pixels_window(sender, receiver, console_handler).await;
+    Ok(arg
+        .strip_prefix(name)
+        .and_then(|rest| rest.strip_prefix('='))
+        .map(String::from))
+}
 
-    Ok(())
+fn usage() -> String {
+    let backends: Vec<String> = Backend::AVAILABLE.iter().map(|backend| backend.to_string()).collect();
+    format!(
+        "Usage: vm_streaming [options]\n\
+         \n\
+         \x20 --backend <{}>  window backend\n\
+         \x20 --connect <spec>  session (default), system, p2p:<path>, fd:<number>, qmp:<path>\n\
+         \x20                   or a D-Bus address\n\
+         \x20 --service <name>  bus name of the VM, {} by default\n\
+         \x20 --list            print the VMs on the bus, with the service to pick them with\n\
+         \x20 --auto-resize     ask the guest to change its resolution to the window size\n\
+         \x20 --cursor <mode>   composite (default) draws the guest cursor in the frame, native\n\
+         \x20                   turns the host pointer into it\n\
+         \x20 --release-hotkey <keys>  keys releasing the pointer grab, ctrl+alt+g by default",
+        backends.join("|"),
+        DEFAULT_SERVICE
+    )
 }
 
+/// Options to connect with, taking the fd of `fd:<number>` over.
+fn connect_options(spec: ConnectSpec) -> Result<ConnectOptions, String> {
+    match spec {
+        ConnectSpec::Options(options) => Ok(options),
+        ConnectSpec::Fd(fd) => {
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+                return Err(format!("fd {} isn't open", fd));
+            }
+            // Safety: the fd was handed to us by whoever started us and named it on the command
+            // line, nothing else in this process uses it
+            Ok(unsafe { ConnectOptions::from_raw_fd(fd) })
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Diagnostics filtered with RUST_LOG, e.g. RUST_LOG=vm_streaming=debug, info by default
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_span_events(FmtSpan::NEW)
+        .init();
 
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", usage());
+            return Ok(());
+        }
+        Err(e) => return Err(format!("{}\n{}", e, usage()).into()),
+    };
 
+    // Attach to the graphical console of the VM, and again whenever QEMU restarts
+    let options = connect_options(args.connect)?;
+    if args.list {
+        let connection = options.connect().await?;
+        for vm in discover(&connection).await? {
+            println!("{}\t{}\t{}", vm.service, vm.name, vm.uuid);
+        }
+        return Ok(());
+    }
 
+    let supervisor = ConsoleSupervisor::new(options, &args.service);
 
+    args.backend.run(supervisor, args.options).await;
 
+    Ok(())
+}